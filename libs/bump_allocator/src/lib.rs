@@ -10,21 +10,32 @@
 #![feature(const_fn)]
 #![feature(allocator)]
 
-#![allocator]
+#![cfg_attr(not(test), allocator)]
 #![no_std]
 
-use spin::Mutex;
-
 extern crate spin;
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::{ptr, cmp};
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
+// the start of the kernel's virtual address window in the higher half
+pub const HEAP_START: usize = 0o177777_775_000_000_000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
-static BUMP_ALLOCATOR: Mutex<BumpAllocator> = Mutex::new(
-    BumpAllocator::new(HEAP_START, HEAP_SIZE));
+// The `#[no_mangle]` allocation functions. They would replace the system allocator of the test
+// binary, so they're only compiled for the kernel.
+#[cfg(not(test))]
+mod global;
+
+#[cfg(test)]
+#[path = "../../random_allocations.rs"]
+mod random_allocations;
 
+/// A bump allocator over an arbitrary memory region. It never frees memory.
 #[derive(Debug)]
-struct BumpAllocator {
+pub struct BumpAllocator {
     heap_start: usize,
     heap_size: usize,
     next: usize,
@@ -33,7 +44,7 @@ struct BumpAllocator {
 impl BumpAllocator {
     /// Create a new allocator, which uses the memory in the
     /// range [heap_start, heap_start + heap_size).
-    const fn new(heap_start: usize, heap_size: usize) -> BumpAllocator {
+    pub const fn new(heap_start: usize, heap_size: usize) -> BumpAllocator {
         BumpAllocator {
             heap_start: heap_start,
             heap_size: heap_size,
@@ -42,7 +53,7 @@ impl BumpAllocator {
     }
 
     /// Allocates a block of memory with the given size and alignment.
-    fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
         let alloc_start = align_up(self.next, align);
         let alloc_end = alloc_start.saturating_add(size);
 
@@ -53,6 +64,20 @@ impl BumpAllocator {
             None
         }
     }
+
+    /// Moves the given block to a new block of `new_size` bytes. The old block is leaked.
+    pub unsafe fn reallocate(&mut self,
+                             ptr: *mut u8,
+                             size: usize,
+                             new_size: usize,
+                             align: usize)
+                             -> Option<*mut u8> {
+        let new_ptr = self.allocate(new_size, align);
+        if let Some(new_ptr) = new_ptr {
+            ptr::copy(ptr, new_ptr, cmp::min(size, new_size));
+        }
+        new_ptr
+    }
 }
 
 /// Align downwards. Returns the greatest x with alignment `align`
@@ -73,42 +98,77 @@ pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
 
-#[no_mangle]
-pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    BUMP_ALLOCATOR.lock().allocate(size, align).expect("out of memory")
-}
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::{BumpAllocator, align_up, align_down};
+    use random_allocations::RandomAllocations;
 
-#[no_mangle]
-pub extern fn __rust_deallocate(_ptr: *mut u8, _size: usize,
-    _align: usize)
-{
-    // just leak it
-}
+    fn allocator(memory: &mut Vec<u8>) -> BumpAllocator {
+        BumpAllocator::new(memory.as_mut_ptr() as usize, memory.len())
+    }
 
-#[no_mangle]
-pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
-    size
-}
+    #[test]
+    fn align() {
+        assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(align_up(0x1000, 0x1000), 0x1000);
+        assert_eq!(align_down(0x1fff, 0x1000), 0x1000);
+        assert_eq!(align_up(13, 1), 13);
+        assert_eq!(align_down(13, 0), 13);
+    }
 
-#[no_mangle]
-pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
-    _new_size: usize, _align: usize) -> usize
-{
-    size
-}
+    #[test]
+    #[should_panic]
+    fn align_not_power_of_two() {
+        align_up(13, 3);
+    }
 
-#[no_mangle]
-pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
-                                align: usize) -> *mut u8 {
-    use core::{ptr, cmp};
+    #[test]
+    fn allocate_until_full() {
+        let mut memory = vec![0u8; 1024];
+        let start = memory.as_ptr() as usize;
+        let mut allocator = allocator(&mut memory);
+
+        let first = allocator.allocate(1000, 1).unwrap() as usize;
+        assert_eq!(first, start);
+        assert!(allocator.allocate(25, 1).is_none());
+        let second = allocator.allocate(24, 1).unwrap() as usize;
+        assert_eq!(second, start + 1000);
+        assert!(allocator.allocate(1, 1).is_none());
+        // the end address would overflow
+        assert!(allocator.allocate(usize::max_value(), 1).is_none());
+    }
 
-    // from: https://github.com/rust-lang/rust/blob/
-    //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
-    //     src/liballoc_system/lib.rs#L98-L101
+    #[test]
+    fn reallocate_copies() {
+        let mut memory = vec![0u8; 1024];
+        let mut allocator = allocator(&mut memory);
 
-    let new_ptr = __rust_allocate(new_size, align);
-    unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
-    __rust_deallocate(ptr, size, align);
-    new_ptr
-}
+        let ptr = allocator.allocate(16, 8).unwrap();
+        for i in 0..16 {
+            unsafe { *ptr.offset(i) = i as u8 };
+        }
+        let new_ptr = unsafe { allocator.reallocate(ptr, 16, 64, 8).unwrap() };
+        assert!(new_ptr as usize >= ptr as usize + 16);
+        for i in 0..16 {
+            assert_eq!(unsafe { *new_ptr.offset(i) }, i as u8);
+        }
+    }
 
+    #[test]
+    fn random_allocations() {
+        let mut memory = vec![0u8; 64 * 1024];
+        let (start, end) = (memory.as_ptr() as usize, memory.as_ptr() as usize + memory.len());
+        let mut allocator = allocator(&mut memory);
+        let mut random = RandomAllocations::new();
+
+        // the bump allocator never frees, so we allocate until it's full
+        while let Some(block) = random.allocate(512, |size, align| {
+            allocator.allocate(size, align)
+        }) {
+            assert!(block.address >= start && block.address + block.size <= end);
+        }
+        assert!(random.len() > 100);
+        random.remove_all();
+    }
+}
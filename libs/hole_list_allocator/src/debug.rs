@@ -0,0 +1,79 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The debug heap mode. Every allocation is surrounded by red zones that are filled with a guard
+//! pattern and checked when the allocation is freed or reallocated. Fresh and freed memory are
+//! filled with distinct poison patterns, so that reads of uninitialized or freed memory stand out.
+
+use core::{cmp, ptr, slice};
+
+/// The minimal size of the red zones in front of and behind every allocation.
+pub const RED_ZONE_SIZE: usize = 16;
+
+/// The pattern of the red zones.
+pub const GUARD_BYTE: u8 = 0xfd;
+/// The pattern of freshly allocated memory.
+pub const ALLOCATED_BYTE: u8 = 0xcd;
+/// The pattern of freed memory.
+pub const FREED_BYTE: u8 = 0xdd;
+
+/// Returns the size of the front red zone. It's a multiple of `align`, so that the returned
+/// pointer keeps the requested alignment (both are powers of two).
+fn front_size(align: usize) -> usize {
+    cmp::max(RED_ZONE_SIZE, align)
+}
+
+/// Returns the size of the block that needs to be allocated for an allocation of `size` bytes.
+pub fn outer_size(size: usize, align: usize) -> usize {
+    front_size(align) + size + RED_ZONE_SIZE
+}
+
+/// Fills the red zones and the allocation in the given block and returns the address of the
+/// allocation. The block must be `outer_size(size, align)` bytes large.
+pub unsafe fn init_block(block: *mut u8, size: usize, align: usize) -> *mut u8 {
+    let front = front_size(align);
+    let ptr = block.offset(front as isize);
+
+    ptr::write_bytes(block, GUARD_BYTE, front);
+    ptr::write_bytes(ptr, ALLOCATED_BYTE, size);
+    ptr::write_bytes(ptr.offset(size as isize), GUARD_BYTE, RED_ZONE_SIZE);
+    ptr
+}
+
+/// Checks the red zones of the given allocation and panics if they were overwritten.
+pub unsafe fn check_block(ptr: *mut u8, size: usize, align: usize) {
+    let front = front_size(align);
+    let before = slice::from_raw_parts(ptr.offset(-(front as isize)), front);
+    let after = slice::from_raw_parts(ptr.offset(size as isize), RED_ZONE_SIZE);
+
+    if let Some(index) = before.iter().rposition(|&byte| byte != GUARD_BYTE) {
+        panic!("heap corruption: red zone of allocation {:#x} ({} bytes) overwritten at \
+                offset -{}",
+               ptr as usize,
+               size,
+               front - index);
+    }
+    if let Some(index) = after.iter().position(|&byte| byte != GUARD_BYTE) {
+        panic!("heap corruption: red zone of allocation {:#x} ({} bytes) overwritten at \
+                offset {}",
+               ptr as usize,
+               size,
+               size + index);
+    }
+}
+
+/// Checks the red zones of the given allocation, poisons the whole block and returns its start
+/// address.
+pub unsafe fn free_block(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+    check_block(ptr, size, align);
+
+    let block = ptr.offset(-(front_size(align) as isize));
+    ptr::write_bytes(block, FREED_BYTE, outer_size(size, align));
+    block
+}
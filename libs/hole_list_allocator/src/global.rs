@@ -0,0 +1,123 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use spin::Mutex;
+use {GrowableHeap, GrowHandler, HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE};
+#[cfg(feature = "stats")]
+use stats;
+#[cfg(feature = "debug_heap")]
+use debug;
+
+lazy_static! {
+    static ref HEAP: Mutex<GrowableHeap> =
+        Mutex::new(unsafe { GrowableHeap::new(HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE) });
+}
+
+/// Sets the function that is used to map more memory when the heap is full. Without a handler,
+/// the heap stays at `HEAP_SIZE`.
+pub fn set_grow_handler(handler: GrowHandler) {
+    HEAP.lock().set_grow_handler(handler);
+}
+
+/// Returns the current size of the heap in bytes.
+pub fn heap_size() -> usize {
+    HEAP.lock().size()
+}
+
+#[cfg(feature = "stats")]
+fn track_allocation(ptr: *mut u8, size: usize) {
+    stats::record_allocation(ptr as usize, size)
+}
+
+#[cfg(not(feature = "stats"))]
+fn track_allocation(_ptr: *mut u8, _size: usize) {}
+
+#[cfg(feature = "stats")]
+fn track_deallocation(ptr: *mut u8, size: usize) {
+    stats::record_deallocation(ptr as usize, size)
+}
+
+#[cfg(not(feature = "stats"))]
+fn track_deallocation(_ptr: *mut u8, _size: usize) {}
+
+fn allocate_from_heap(size: usize, align: usize) -> *mut u8 {
+    HEAP.lock().allocate(size, align).expect("out of memory")
+}
+
+#[cfg(feature = "debug_heap")]
+fn allocate(size: usize, align: usize) -> *mut u8 {
+    let block = allocate_from_heap(debug::outer_size(size, align), align);
+    unsafe { debug::init_block(block, size, align) }
+}
+
+#[cfg(not(feature = "debug_heap"))]
+fn allocate(size: usize, align: usize) -> *mut u8 {
+    allocate_from_heap(size, align)
+}
+
+#[cfg(feature = "debug_heap")]
+unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize) {
+    // check the red zones first, so that the heap isn't locked if the check panics
+    let block = debug::free_block(ptr, size, align);
+    HEAP.lock().deallocate(block, debug::outer_size(size, align), align)
+}
+
+#[cfg(not(feature = "debug_heap"))]
+unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize) {
+    HEAP.lock().deallocate(ptr, size, align)
+}
+
+#[cfg(feature = "debug_heap")]
+fn check_allocation(ptr: *mut u8, size: usize, align: usize) {
+    unsafe { debug::check_block(ptr, size, align) }
+}
+
+#[cfg(not(feature = "debug_heap"))]
+fn check_allocation(_ptr: *mut u8, _size: usize, _align: usize) {}
+
+#[no_mangle]
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    let ptr = allocate(size, align);
+    track_allocation(ptr, size);
+    ptr
+}
+
+#[no_mangle]
+pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    unsafe { deallocate(ptr, size, align) };
+    track_deallocation(ptr, size);
+}
+
+#[no_mangle]
+pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
+    size
+}
+
+#[no_mangle]
+pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
+    _new_size: usize, _align: usize) -> usize
+{
+    size
+}
+
+#[no_mangle]
+pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
+                                align: usize) -> *mut u8 {
+    use core::{ptr, cmp};
+
+    // from: https://github.com/rust-lang/rust/blob/
+    //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
+    //     src/liballoc_system/lib.rs#L98-L101
+
+    check_allocation(ptr, size, align);
+    let new_ptr = __rust_allocate(new_size, align);
+    unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
+    __rust_deallocate(ptr, size, align);
+    new_ptr
+}
@@ -0,0 +1,251 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use linked_list_allocator::Heap;
+use core::{ptr, cmp};
+#[cfg(feature = "slab_allocator")]
+use slab_allocator::SlabAllocator;
+
+/// The heap grows at least by this amount, so that we don't need to grow it on every allocation.
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// A function that maps `size` bytes of memory at the address `start` for the heap. It returns
+/// `false` if there is not enough memory. It is called while the heap is locked, so it must not
+/// allocate itself.
+pub type GrowHandler = fn(start: usize, size: usize) -> bool;
+
+/// A hole list heap over an arbitrary memory region, which can grow up to a maximum size.
+///
+/// The heap starts with `size` bytes. When it is full, it asks the grow handler to map more
+/// memory behind its end. Without a handler, it never grows.
+pub struct GrowableHeap {
+    heap: Heap,
+    start: usize,
+    size: usize,
+    max_size: usize,
+    grow_handler: Option<GrowHandler>,
+    #[cfg(feature = "slab_allocator")]
+    slabs: SlabAllocator,
+}
+
+impl GrowableHeap {
+    /// Creates a heap in the memory range `[start, start + size)`. The range must be valid,
+    /// writable, and unused.
+    #[cfg(not(feature = "slab_allocator"))]
+    pub unsafe fn new(start: usize, size: usize, max_size: usize) -> GrowableHeap {
+        GrowableHeap {
+            heap: Heap::new(start, size),
+            start: start,
+            size: size,
+            max_size: max_size,
+            grow_handler: None,
+        }
+    }
+
+    /// Creates a heap in the memory range `[start, start + size)`. The range must be valid,
+    /// writable, and unused.
+    #[cfg(feature = "slab_allocator")]
+    pub unsafe fn new(start: usize, size: usize, max_size: usize) -> GrowableHeap {
+        GrowableHeap {
+            heap: Heap::new(start, size),
+            start: start,
+            size: size,
+            max_size: max_size,
+            grow_handler: None,
+            slabs: SlabAllocator::new(),
+        }
+    }
+
+    /// Sets the function that is used to map more memory when the heap is full.
+    pub fn set_grow_handler(&mut self, handler: GrowHandler) {
+        self.grow_handler = Some(handler);
+    }
+
+    /// Returns the current size of the heap in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Allocates a block of memory with the given size and alignment. The heap is grown if
+    /// necessary. Returns `None` if it can't grow any further.
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        loop {
+            if let Some(ptr) = self.allocate_block(size, align) {
+                return Some(ptr);
+            }
+            // the new memory might not be adjacent to the last hole, so we need to grow by the
+            // full size (plus the alignment padding)
+            if !self.grow(size + align) {
+                return None;
+            }
+        }
+    }
+
+    /// Frees the given block. `size` and `align` must be the values that were used for the
+    /// allocation.
+    #[cfg(not(feature = "slab_allocator"))]
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        self.heap.deallocate(ptr, size, align)
+    }
+
+    /// Frees the given block. `size` and `align` must be the values that were used for the
+    /// allocation.
+    #[cfg(feature = "slab_allocator")]
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        self.slabs.deallocate(ptr, size, align, &mut self.heap)
+    }
+
+    /// Moves the given block to a new block of `new_size` bytes. The old block is only freed if
+    /// the allocation succeeds.
+    pub unsafe fn reallocate(&mut self,
+                             ptr: *mut u8,
+                             size: usize,
+                             new_size: usize,
+                             align: usize)
+                             -> Option<*mut u8> {
+        let new_ptr = self.allocate(new_size, align);
+        if let Some(new_ptr) = new_ptr {
+            ptr::copy(ptr, new_ptr, cmp::min(size, new_size));
+            self.deallocate(ptr, size, align);
+        }
+        new_ptr
+    }
+
+    #[cfg(not(feature = "slab_allocator"))]
+    fn allocate_block(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        self.heap.allocate_first_fit(size, align)
+    }
+
+    #[cfg(feature = "slab_allocator")]
+    fn allocate_block(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        self.slabs.allocate(size, align, &mut self.heap)
+    }
+
+    /// Tries to grow the heap by at least `min_size` bytes, but not beyond `max_size`.
+    fn grow(&mut self, min_size: usize) -> bool {
+        let handler = match self.grow_handler {
+            Some(handler) => handler,
+            None => return false,
+        };
+
+        let mut size = (cmp::max(min_size, HEAP_GROW_SIZE) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        size = cmp::min(size, self.max_size - self.size);
+        if size == 0 || !handler(self.start + self.size, size) {
+            return false;
+        }
+
+        // freeing the new memory adds it to the hole list (merged with a hole at the heap end)
+        unsafe { self.heap.deallocate((self.start + self.size) as *mut u8, size, 1) };
+        self.size += size;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use core::mem;
+    use super::{GrowableHeap, HEAP_GROW_SIZE, PAGE_SIZE};
+    use random_allocations::RandomAllocations;
+
+    /// Returns `size` bytes of memory for a heap. It consists of `usize`s, so that it is aligned
+    /// for the hole list.
+    fn memory(size: usize) -> Vec<usize> {
+        vec![0; size / mem::size_of::<usize>()]
+    }
+
+    // the test memory is allocated up to the maximum size, so the heap can always grow
+    fn grow_always(_start: usize, _size: usize) -> bool {
+        true
+    }
+
+    fn grow_never(_start: usize, _size: usize) -> bool {
+        false
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let mut memory = memory(PAGE_SIZE);
+        let mut heap = unsafe {
+            GrowableHeap::new(memory.as_mut_ptr() as usize, PAGE_SIZE, PAGE_SIZE)
+        };
+
+        let first = heap.allocate(1024, 8).unwrap();
+        let second = heap.allocate(1024, 8).unwrap();
+        assert!(second as usize >= first as usize + 1024);
+        assert!(heap.allocate(4096, 8).is_none());
+
+        unsafe { heap.deallocate(first, 1024, 8) };
+        assert_eq!(heap.allocate(1024, 8), Some(first));
+    }
+
+    #[test]
+    fn grow_up_to_max_size() {
+        let max_size = 4 * HEAP_GROW_SIZE;
+        let mut memory = memory(max_size);
+        let start = memory.as_mut_ptr() as usize;
+        let mut heap = unsafe { GrowableHeap::new(start, PAGE_SIZE, max_size) };
+
+        // without a handler, the heap can't grow
+        assert!(heap.allocate(2 * PAGE_SIZE, 8).is_none());
+        assert_eq!(heap.size(), PAGE_SIZE);
+
+        heap.set_grow_handler(grow_always);
+        let block = heap.allocate(2 * PAGE_SIZE, 8).unwrap() as usize;
+        assert_eq!(heap.size(), PAGE_SIZE + HEAP_GROW_SIZE);
+        assert!(block >= start && block + 2 * PAGE_SIZE <= start + heap.size());
+
+        assert!(heap.allocate(max_size, 8).is_none());
+        assert_eq!(heap.size(), max_size);
+    }
+
+    #[test]
+    fn grow_refused() {
+        let mut memory = memory(PAGE_SIZE);
+        let mut heap = unsafe {
+            GrowableHeap::new(memory.as_mut_ptr() as usize, PAGE_SIZE, HEAP_GROW_SIZE)
+        };
+        heap.set_grow_handler(grow_never);
+
+        assert!(heap.allocate(2 * PAGE_SIZE, 8).is_none());
+        assert_eq!(heap.size(), PAGE_SIZE);
+        assert!(heap.allocate(PAGE_SIZE / 2, 8).is_some());
+    }
+
+    #[test]
+    fn random_allocations() {
+        let max_size = 16 * HEAP_GROW_SIZE;
+        let mut memory = memory(max_size);
+        let start = memory.as_mut_ptr() as usize;
+        let mut heap = unsafe { GrowableHeap::new(start, PAGE_SIZE, max_size) };
+        heap.set_grow_handler(grow_always);
+
+        let mut random = RandomAllocations::new();
+
+        for _ in 0..10000 {
+            if random.len() == 0 || (random.len() < 256 && random.below(3) != 0) {
+                let block = random.allocate(2048, |size, align| heap.allocate(size, align))
+                    .expect("out of memory");
+                assert!(block.address >= start &&
+                        block.address + block.size <= start + heap.size());
+            } else {
+                let block = random.remove_random();
+                unsafe { heap.deallocate(block.address as *mut u8, block.size, block.align) };
+            }
+        }
+        assert!(heap.size() > PAGE_SIZE);
+
+        for block in random.remove_all() {
+            unsafe { heap.deallocate(block.address as *mut u8, block.size, block.align) };
+        }
+        // all holes were merged again
+        assert!(heap.allocate(heap.size() / 2, 8).is_some());
+    }
+}
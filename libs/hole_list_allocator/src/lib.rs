@@ -9,60 +9,42 @@
 
 #![feature(allocator)]
 #![feature(const_fn)]
+#![cfg_attr(feature = "stats", feature(asm))]
 
-#![allocator]
+#![cfg_attr(not(test), allocator)]
 #![no_std]
 
-use spin::Mutex;
-use linked_list_allocator::Heap;
-
 extern crate spin;
 extern crate linked_list_allocator;
+#[cfg(not(test))]
 #[macro_use]
 extern crate lazy_static;
-
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
-
-lazy_static! {
-    static ref HEAP: Mutex<Heap> = Mutex::new(unsafe {
-        Heap::new(HEAP_START, HEAP_SIZE)
-    });
-}
-
-#[no_mangle]
-pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    HEAP.lock().allocate_first_fit(size, align).expect("out of memory")
-}
-
-#[no_mangle]
-pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
-    unsafe { HEAP.lock().deallocate(ptr, size, align) };
-}
-
-#[no_mangle]
-pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
-    size
-}
-
-#[no_mangle]
-pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
-    _new_size: usize, _align: usize) -> usize
-{
-    size
-}
-
-#[no_mangle]
-pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
-                                align: usize) -> *mut u8 {
-    use core::{ptr, cmp};
-
-    // from: https://github.com/rust-lang/rust/blob/
-    //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
-    //     src/liballoc_system/lib.rs#L98-L101
-
-    let new_ptr = __rust_allocate(new_size, align);
-    unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
-    __rust_deallocate(ptr, size, align);
-    new_ptr
-}
+#[cfg(feature = "slab_allocator")]
+extern crate slab_allocator;
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub use heap::{GrowableHeap, GrowHandler};
+#[cfg(not(test))]
+pub use global::{set_grow_handler, heap_size};
+
+mod heap;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(feature = "debug_heap")]
+pub mod debug;
+
+// The kernel heap and the `#[no_mangle]` allocation functions. They would replace the system
+// allocator of the test binary, so they're only compiled for the kernel.
+#[cfg(not(test))]
+mod global;
+
+#[cfg(test)]
+#[path = "../../random_allocations.rs"]
+mod random_allocations;
+
+// the start of the kernel's virtual address window in the higher half
+pub const HEAP_START: usize = 0o177777_775_000_000_000_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB initially
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
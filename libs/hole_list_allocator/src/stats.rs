@@ -0,0 +1,149 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Allocation statistics and leak tracking. The bookkeeping can't use the heap itself, so the
+//! live allocations are stored in a fixed size table.
+
+use spin::Mutex;
+
+/// The maximum number of live allocations that are tracked. Further allocations are only counted.
+const MAX_TRACKED_ALLOCATIONS: usize = 1024;
+
+/// The number of return addresses that are recorded for each allocation.
+pub const BACKTRACE_DEPTH: usize = 8;
+
+/// The number of size buckets. Bucket `i` counts allocations of at most `16 << i` bytes, the last
+/// bucket counts all larger allocations.
+pub const SIZE_BUCKETS: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub buckets: [usize; SIZE_BUCKETS],
+    /// The number of live allocations that didn't fit into the table.
+    pub untracked: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    /// The return addresses of the allocating call stack, innermost first. The first entries
+    /// point into the allocator itself. Unused entries are 0.
+    pub callers: [usize; BACKTRACE_DEPTH],
+}
+
+struct Tracker {
+    stats: HeapStats,
+    // entries with address 0 are unused
+    allocations: [Allocation; MAX_TRACKED_ALLOCATIONS],
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    stats: HeapStats {
+        live_bytes: 0,
+        peak_bytes: 0,
+        allocations: 0,
+        deallocations: 0,
+        buckets: [0; SIZE_BUCKETS],
+        untracked: 0,
+    },
+    allocations: [Allocation {
+        address: 0,
+        size: 0,
+        callers: [0; BACKTRACE_DEPTH],
+    }; MAX_TRACKED_ALLOCATIONS],
+});
+
+pub fn record_allocation(address: usize, size: usize) {
+    let callers = backtrace();
+    let mut guard = TRACKER.lock();
+    let tracker = &mut *guard;
+
+    tracker.stats.allocations += 1;
+    tracker.stats.live_bytes += size;
+    if tracker.stats.live_bytes > tracker.stats.peak_bytes {
+        tracker.stats.peak_bytes = tracker.stats.live_bytes;
+    }
+    let bucket = (0..SIZE_BUCKETS - 1).find(|&i| size <= 16 << i).unwrap_or(SIZE_BUCKETS - 1);
+    tracker.stats.buckets[bucket] += 1;
+
+    match tracker.allocations.iter_mut().find(|a| a.address == 0) {
+        Some(entry) => {
+            *entry = Allocation {
+                address: address,
+                size: size,
+                callers: callers,
+            }
+        }
+        None => tracker.stats.untracked += 1,
+    }
+}
+
+pub fn record_deallocation(address: usize, size: usize) {
+    let mut guard = TRACKER.lock();
+    let tracker = &mut *guard;
+
+    tracker.stats.deallocations += 1;
+    tracker.stats.live_bytes -= size;
+
+    match tracker.allocations.iter_mut().find(|a| a.address == address) {
+        Some(entry) => entry.address = 0,
+        None => tracker.stats.untracked = tracker.stats.untracked.saturating_sub(1),
+    }
+}
+
+/// Returns the current statistics, or `None` if they are locked (e.g. when called from a panic
+/// inside the allocator).
+pub fn heap_stats() -> Option<HeapStats> {
+    TRACKER.try_lock().map(|tracker| tracker.stats)
+}
+
+/// Calls `f` for every live allocation in the table. `f` must not allocate, since the table is
+/// locked while it runs.
+pub fn for_each_allocation<F>(mut f: F)
+    where F: FnMut(&Allocation)
+{
+    if let Some(tracker) = TRACKER.try_lock() {
+        for allocation in tracker.allocations.iter().filter(|a| a.address != 0) {
+            f(allocation);
+        }
+    }
+}
+
+/// Walks the frame pointer chain. The target spec forces frame pointers and the chain ends with a
+/// zero `rbp` at the bottom of each kernel stack. We still stop at frame pointers that are
+/// misaligned or don't lie above the previous frame on the stack, so that a broken chain can't
+/// make us read arbitrary memory.
+fn backtrace() -> [usize; BACKTRACE_DEPTH] {
+    let mut callers = [0; BACKTRACE_DEPTH];
+    let (mut rbp, rsp): (usize, usize);
+    unsafe {
+        asm!("mov $0, rbp
+              mov $1, rsp"
+             : "=r"(rbp), "=r"(rsp) ::: "intel")
+    };
+
+    // the stack grows downwards, so the frames of the callers lie at higher addresses
+    let mut lower_bound = rsp;
+    for caller in callers.iter_mut() {
+        if rbp == 0 || rbp % 8 != 0 || rbp < lower_bound {
+            break;
+        }
+        unsafe {
+            *caller = *((rbp + 8) as *const usize);
+            lower_bound = rbp + 16;
+            rbp = *(rbp as *const usize);
+        }
+    }
+    callers
+}
@@ -0,0 +1,112 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The randomized allocation tests of the allocator crates. Each crate includes this file as a
+//! test module through `#[path]`, so that it doesn't need a crate of its own.
+
+// not every crate needs every helper, e.g. the bump allocator never frees blocks
+#![allow(dead_code)]
+
+use std::vec::Vec;
+use core::ptr;
+
+/// A block that was handed out by the allocator under test.
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub address: usize,
+    pub size: usize,
+    pub align: usize,
+    // the byte the block is filled with
+    pattern: u8,
+}
+
+impl Block {
+    /// Panics if the block was overwritten since it was allocated.
+    fn check(&self) {
+        for address in self.address..self.address + self.size {
+            assert_eq!(unsafe { *(address as *const u8) }, self.pattern);
+        }
+    }
+}
+
+/// Allocates blocks of random sizes and alignments and keeps track of the live ones. Each block is
+/// filled with its own pattern, so that overlapping blocks are detected.
+pub struct RandomAllocations {
+    // a xorshift generator, so that the tests are reproducible
+    state: u64,
+    blocks: Vec<Block>,
+    allocations: usize,
+}
+
+impl RandomAllocations {
+    pub fn new() -> RandomAllocations {
+        RandomAllocations {
+            state: 0x2545_f491_4f6c_dd1d,
+            blocks: Vec::new(),
+            allocations: 0,
+        }
+    }
+
+    /// Returns a random number in `[0, bound)`.
+    pub fn below(&mut self, bound: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % bound as u64) as usize
+    }
+
+    /// Returns the number of live blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Allocates a block of at most `max_size` bytes with an alignment of up to 64 through
+    /// `allocate`. Returns the new block or `None` if the allocation failed.
+    pub fn allocate<F>(&mut self, max_size: usize, allocate: F) -> Option<Block>
+        where F: FnOnce(usize, usize) -> Option<*mut u8>
+    {
+        let size = self.below(max_size) + 1;
+        let align = 1 << self.below(7);
+        let address = match allocate(size, align) {
+            Some(ptr) => ptr as usize,
+            None => return None,
+        };
+        assert_eq!(address % align, 0);
+        for other in self.blocks.iter() {
+            assert!(address + size <= other.address || other.address + other.size <= address);
+        }
+
+        let block = Block {
+            address: address,
+            size: size,
+            align: align,
+            pattern: self.allocations as u8,
+        };
+        unsafe { ptr::write_bytes(address as *mut u8, block.pattern, size) };
+        self.blocks.push(block);
+        self.allocations += 1;
+        Some(block)
+    }
+
+    /// Removes a random live block after checking its contents. The caller frees it.
+    pub fn remove_random(&mut self) -> Block {
+        let index = self.below(self.blocks.len());
+        let block = self.blocks.swap_remove(index);
+        block.check();
+        block
+    }
+
+    /// Removes all live blocks after checking their contents. The caller frees them.
+    pub fn remove_all(&mut self) -> Vec<Block> {
+        for block in self.blocks.iter() {
+            block.check();
+        }
+        self.blocks.drain(..).collect()
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+extern crate linked_list_allocator;
+
+use linked_list_allocator::Heap;
+use core::cmp;
+
+/// The object sizes of the size classes. All of them are powers of two.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The size of a slab. Slabs are aligned to their size, so every object is aligned to its size.
+const SLAB_SIZE: usize = 4096;
+
+/// A size class allocator. Small blocks are served from per-class slabs, larger blocks from the
+/// given fallback `Heap`. The slabs are allocated from the fallback heap, too.
+///
+/// Freed objects are kept on the free list of their size class, so empty slabs are never returned
+/// to the fallback heap.
+pub struct SlabAllocator {
+    // the address of the first free object of each class, or 0 if the list is empty. Each free
+    // object contains the address of the next one.
+    free_lists: [usize; 8],
+}
+
+impl SlabAllocator {
+    pub fn new() -> SlabAllocator {
+        SlabAllocator { free_lists: [0; 8] }
+    }
+
+    /// Allocates a block of memory with the given size and alignment. Returns `None` if the
+    /// fallback heap is out of memory.
+    pub fn allocate(&mut self, size: usize, align: usize, heap: &mut Heap) -> Option<*mut u8> {
+        let class = match size_class(size, align) {
+            Some(class) => class,
+            None => return heap.allocate_first_fit(size, align),
+        };
+
+        if self.free_lists[class] == 0 && !self.add_slab(class, heap) {
+            return None;
+        }
+        let object = self.free_lists[class];
+        self.free_lists[class] = unsafe { *(object as *const usize) };
+        Some(object as *mut u8)
+    }
+
+    /// Frees the given block. `size` and `align` must be the values that were used for the
+    /// allocation.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize, align: usize, heap: &mut Heap) {
+        match size_class(size, align) {
+            Some(class) => self.push(class, ptr as usize),
+            None => heap.deallocate(ptr, size, align),
+        }
+    }
+
+    /// Allocates a new slab from the heap and adds its objects to the free list of `class`.
+    fn add_slab(&mut self, class: usize, heap: &mut Heap) -> bool {
+        let slab = match heap.allocate_first_fit(SLAB_SIZE, SLAB_SIZE) {
+            Some(slab) => slab as usize,
+            None => return false,
+        };
+        let object_size = SIZE_CLASSES[class];
+        // push in reverse order, so that objects are handed out in address order
+        for object in (0..SLAB_SIZE / object_size).rev() {
+            self.push(class, slab + object * object_size);
+        }
+        true
+    }
+
+    fn push(&mut self, class: usize, object: usize) {
+        unsafe { *(object as *mut usize) = self.free_lists[class] };
+        self.free_lists[class] = object;
+    }
+}
+
+/// Returns the index of the smallest size class that satisfies the given size and alignment.
+fn size_class(size: usize, align: usize) -> Option<usize> {
+    let size = cmp::max(size, align);
+    SIZE_CLASSES.iter().position(|&class_size| class_size >= size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use std::mem;
+    use linked_list_allocator::Heap;
+    use super::{SlabAllocator, size_class, SIZE_CLASSES, SLAB_SIZE};
+
+    /// Returns `size` bytes of memory for the fallback heap. It consists of `usize`s, so that it
+    /// is aligned for the hole list.
+    fn memory(size: usize) -> Vec<usize> {
+        vec![0; size / mem::size_of::<usize>()]
+    }
+
+    fn heap(memory: &mut Vec<usize>) -> Heap {
+        unsafe { Heap::new(memory.as_mut_ptr() as usize, memory.len() * mem::size_of::<usize>()) }
+    }
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(size_class(1, 1), Some(0));
+        for (class, &size) in SIZE_CLASSES.iter().enumerate() {
+            assert_eq!(size_class(size, 8), Some(class));
+            assert_eq!(size_class(size / 2 + 1, 8), Some(class));
+        }
+        assert_eq!(size_class(2049, 8), None);
+        // the alignment is used if it's larger than the size
+        assert_eq!(size_class(8, 64), Some(2));
+        assert_eq!(size_class(16, 4096), None);
+    }
+
+    #[test]
+    fn objects_are_aligned() {
+        // one slab for each class and one for aligning the heap
+        let mut memory = memory(10 * SLAB_SIZE);
+        let mut heap = heap(&mut memory);
+        let mut allocator = SlabAllocator::new();
+
+        for &size in SIZE_CLASSES.iter() {
+            let object = allocator.allocate(size, 1, &mut heap).unwrap() as usize;
+            assert_eq!(object % size, 0);
+        }
+        let object = allocator.allocate(24, 256, &mut heap).unwrap() as usize;
+        assert_eq!(object % 256, 0);
+    }
+
+    #[test]
+    fn refill_slab() {
+        let mut memory = memory(4 * SLAB_SIZE);
+        let mut heap = heap(&mut memory);
+        let mut allocator = SlabAllocator::new();
+
+        // the objects of the first slab are handed out in address order
+        let first = allocator.allocate(16, 8, &mut heap).unwrap() as usize;
+        assert_eq!(first % SLAB_SIZE, 0);
+        for i in 1..SLAB_SIZE / 16 {
+            let object = allocator.allocate(16, 8, &mut heap).unwrap() as usize;
+            assert_eq!(object, first + i * 16);
+        }
+        // the slab is full, so the next object comes from a new slab
+        let object = allocator.allocate(16, 8, &mut heap).unwrap() as usize;
+        assert_eq!(object % SLAB_SIZE, 0);
+        assert!(object != first);
+    }
+
+    #[test]
+    fn refill_fails_without_memory() {
+        let mut memory = memory(SLAB_SIZE);
+        let mut heap = heap(&mut memory);
+        let mut allocator = SlabAllocator::new();
+
+        // slabs need aligned memory, so the heap has room for a single slab at most
+        let mut allocated = 0;
+        while allocator.allocate(2048, 8, &mut heap).is_some() {
+            allocated += 1;
+            assert!(allocated <= 2);
+        }
+        assert!(allocator.allocate(2048, 8, &mut heap).is_none());
+    }
+
+    #[test]
+    fn reuse_freed_objects() {
+        let mut memory = memory(4 * SLAB_SIZE);
+        let mut heap = heap(&mut memory);
+        let mut allocator = SlabAllocator::new();
+
+        let first = allocator.allocate(100, 8, &mut heap).unwrap();
+        let second = allocator.allocate(100, 8, &mut heap).unwrap();
+        unsafe {
+            allocator.deallocate(first, 100, 8, &mut heap);
+            allocator.deallocate(second, 100, 8, &mut heap);
+        }
+        // the free list is last in, first out
+        assert_eq!(allocator.allocate(128, 8, &mut heap), Some(second));
+        assert_eq!(allocator.allocate(65, 8, &mut heap), Some(first));
+    }
+
+    #[test]
+    fn fallback_for_large_blocks() {
+        let mut memory = memory(4 * SLAB_SIZE);
+        let mut heap = heap(&mut memory);
+        let mut allocator = SlabAllocator::new();
+
+        let block = allocator.allocate(3000, 8, &mut heap).unwrap();
+        // no slab was allocated
+        assert_eq!(allocator.free_lists, [0; 8]);
+
+        // the block goes back to the heap, so that a block that overlaps it fits again
+        unsafe { allocator.deallocate(block, 3000, 8, &mut heap) };
+        assert_eq!(allocator.free_lists, [0; 8]);
+        assert!(heap.allocate_first_fit(3 * SLAB_SIZE + 3000, 8).is_some());
+    }
+}
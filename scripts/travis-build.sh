@@ -5,6 +5,11 @@ set -e
 # build rust project
 make
 
+# run the allocator tests on the host
+(cd libs/bump_allocator && cargo test)
+(cd libs/hole_list_allocator && cargo test)
+(cd libs/slab_allocator && cargo test)
+
 # check formatting (rustfmt)
 PATH=~/.cargo/bin:$PATH
 cargo fmt -- --write-mode=diff
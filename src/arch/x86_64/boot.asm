@@ -8,12 +8,18 @@
 ; except according to those terms.
 
 global start
+global stack_bottom
+global stack_top
 extern long_mode_start
 
-section .text
+; The kernel is linked at this offset from its physical address. This code runs before paging is
+; enabled, so it needs to use physical addresses for everything outside of the `.boot` section.
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .boot exec
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_OFFSET
     ; Move Multiboot info pointer to edi to pass it to the kernel. We must not
     ; modify the `edi` register until the kernel it called.
     mov edi, ebx
@@ -27,25 +33,33 @@ start:
     call set_up_SSE
 
     ; load the 64-bit GDT
-    lgdt [gdt64.pointer]
+    lgdt [gdt64.pointer - KERNEL_OFFSET]
 
     jmp gdt64.code:long_mode_start
 
 set_up_page_tables:
-    ; recursive map P4
-    mov eax, p4_table
+    ; recursive map P4 (through the second to last entry, the last one is used by the kernel)
+    mov eax, p4_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p4_table + 511 * 8], eax
+    mov [p4_table - KERNEL_OFFSET + 510 * 8], eax
 
-    ; map first P4 entry to P3 table
-    mov eax, p3_table
+    ; map first and last P4 entry to P3 table
+    mov eax, p3_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p4_table], eax
-
-    ; map first P3 entry to P2 table
-    mov eax, p2_table
+    mov [p4_table - KERNEL_OFFSET], eax
+    mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
+%ifdef PHYSICAL_MAP
+    ; the first GiB of the physical memory map (`paging::PHYSICAL_MAP_OFFSET`), which is used to
+    ; reach the frames of the new page tables while the kernel is remapped
+    mov [p4_table - KERNEL_OFFSET + 256 * 8], eax
+%endif
+
+    ; map first P3 entry (identity mapping) and the P3 entry of `KERNEL_OFFSET` (higher half
+    ; mapping) to P2 table
+    mov eax, p2_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p3_table], eax
+    mov [p3_table - KERNEL_OFFSET], eax
+    mov [p3_table - KERNEL_OFFSET + 510 * 8], eax
 
     ; map each P2 entry to a huge 2MiB page
     mov ecx, 0 ; counter variable
@@ -54,7 +68,7 @@ set_up_page_tables:
     mov eax, 0x200000  ; 2MiB
     mul ecx            ; start address of ecx-th page
     or eax, 0b10000011 ; present + writable + huge
-    mov [p2_table + ecx * 8], eax ; map ecx-th entry
+    mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map ecx-th entry
 
     inc ecx            ; increase counter
     cmp ecx, 512       ; if counter == 512, the whole P2 table is mapped
@@ -64,7 +78,7 @@ set_up_page_tables:
 
 enable_paging:
     ; load P4 to cr3 register (cpu uses this to access the P4 table)
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
@@ -199,4 +213,4 @@ gdt64:
     dq (1<<44) | (1<<47) | (1<<43) | (1<<53) ; code segment
 .pointer:
     dw $ - gdt64 - 1
-    dq gdt64
+    dq gdt64 - KERNEL_OFFSET
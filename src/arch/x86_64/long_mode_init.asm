@@ -9,8 +9,11 @@
 
 global long_mode_start
 extern rust_main
+extern stack_top
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .boot exec
 bits 64
 long_mode_start:
     ; load 0 into all data segment registers
@@ -21,14 +24,27 @@ long_mode_start:
     mov fs, ax
     mov gs, ax
 
-    ; call rust main (with multiboot pointer in rdi)
+    ; we still run at the physical address, so jump to the higher half
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+bits 64
+higher_half_start:
+    ; use the higher half address of the stack
+    mov rsp, stack_top
+
+    ; terminate the frame pointer chain for backtraces
+    xor rbp, rbp
+
+    ; call rust main (with the physical multiboot pointer in rdi)
     call rust_main
 .os_returned:
     ; rust main returned, print `OS returned!`
     mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
+    mov [KERNEL_OFFSET + 0xb8000], rax
     mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
+    mov [KERNEL_OFFSET + 0xb8008], rax
     mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
+    mov [KERNEL_OFFSET + 0xb8010], rax
     hlt
@@ -7,7 +7,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use memory::MemoryController;
+use memory::{self, MemoryController};
 use x86::bits64::task::TaskStateSegment;
 use spin::Once;
 
@@ -92,6 +92,13 @@ macro_rules! handler_with_error_code {
 }
 
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const PAGE_FAULT_IST_INDEX: usize = 1;
+
+/// The size of the page fault stack. The page fault handler moves the IST entry down by
+/// `PAGE_FAULT_FRAME_SIZE` while it runs, so that nested page faults (e.g. in the copy-on-write
+/// handler) get a fresh part of the stack instead of overwriting the frame of the outer one.
+const PAGE_FAULT_STACK_PAGES: usize = 4;
+const PAGE_FAULT_FRAME_SIZE: u64 = memory::PAGE_SIZE as u64;
 
 lazy_static! {
     static ref IDT: idt::Idt = {
@@ -102,7 +109,10 @@ lazy_static! {
         idt.set_handler(6, handler!(invalid_opcode_handler));
         idt.set_handler(8, handler_with_error_code!(double_fault_handler))
             .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
-        idt.set_handler(14, handler_with_error_code!(page_fault_handler));
+        // page faults get their own stack, so that stack overflows are reported as page faults
+        // on the guard page instead of double faults
+        idt.set_handler(14, handler_with_error_code!(page_fault_handler))
+            .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
 
         idt
     };
@@ -117,10 +127,13 @@ pub fn init(memory_controller: &mut MemoryController) {
 
     let double_fault_stack = memory_controller.alloc_stack(1)
         .expect("could not allocate double fault stack");
+    let page_fault_stack = memory_controller.alloc_stack(PAGE_FAULT_STACK_PAGES)
+        .expect("could not allocate page fault stack");
 
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.ist[DOUBLE_FAULT_IST_INDEX] = double_fault_stack.top() as u64;
+        tss.ist[PAGE_FAULT_IST_INDEX] = page_fault_stack.top() as u64;
         tss
     });
 
@@ -182,12 +195,58 @@ bitflags! {
     }
 }
 
+/// Moves the page fault entry of the interrupt stack table down (`offset < 0`) or back up.
+///
+/// Exceptions run with interrupts disabled, so nothing else uses the entry in the meantime.
+fn shift_page_fault_stack(offset: i64) {
+    let tss = TSS.try().expect("TSS not initialized") as *const _ as *mut TaskStateSegment;
+    unsafe {
+        // the CPU reads the entry from memory on every page fault
+        let ist = &mut (*tss).ist[PAGE_FAULT_IST_INDEX];
+        *ist = (*ist as i64 + offset) as u64;
+    }
+}
+
 extern "C" fn page_fault_handler(stack_frame: &ExceptionStackFrame, error_code: u64) {
+    // a nested page fault starts below the frame of this one. If the nesting gets too deep, the
+    // guard page of the page fault stack causes a double fault.
+    shift_page_fault_stack(-(PAGE_FAULT_FRAME_SIZE as i64));
+    handle_page_fault(stack_frame, error_code);
+    shift_page_fault_stack(PAGE_FAULT_FRAME_SIZE as i64);
+}
+
+fn handle_page_fault(stack_frame: &ExceptionStackFrame, error_code: u64) {
     use x86::shared::control_regs;
+
+    let address = unsafe { control_regs::cr2() };
+    let error_code = PageFaultErrorCode::from_bits(error_code).unwrap();
+
+    // faults on non-present pages of lazily backed regions (e.g. the heap) are resolved by
+    // mapping a zeroed frame. Returning resumes the faulting instruction.
+    if !error_code.contains(PROTECTION_VIOLATION) && memory::handle_page_fault(address) {
+        return;
+    }
+    // writes to copy-on-write pages get a private copy of the shared frame
+    if error_code.contains(PROTECTION_VIOLATION | CAUSED_BY_WRITE) &&
+       memory::handle_copy_on_write_fault(address) {
+        return;
+    }
+
+    // a fault just below the stack pointer means that the stack overflowed into its guard page
+    let stack_pointer = stack_frame.stack_pointer as usize;
+    if address < stack_pointer && stack_pointer - address <= memory::PAGE_SIZE {
+        println!("\nEXCEPTION: PAGE FAULT caused by a stack overflow into the guard page at \
+                  {:#x}\nerror code: {:?}\n{:#?}",
+                 address,
+                 error_code,
+                 stack_frame);
+        loop {}
+    }
+
     println!("\nEXCEPTION: PAGE FAULT while accessing {:#x}\nerror code: \
                                   {:?}\n{:#?}",
-             unsafe { control_regs::cr2() },
-             PageFaultErrorCode::from_bits(error_code).unwrap(),
+             address,
+             error_code,
              stack_frame);
     loop {}
 }
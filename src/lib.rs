@@ -34,39 +34,101 @@ extern crate alloc;
 #[macro_use]
 extern crate collections;
 
+use memory::MemoryController;
+use spin::Mutex;
+
 #[macro_use]
 mod vga_buffer;
 mod memory;
 
 mod interrupts;
 
+/// The size of the kernel stack that replaces the small boot stack.
+const KERNEL_STACK_PAGES: usize = 16;
+
 #[no_mangle]
 pub extern "C" fn rust_main(multiboot_information_address: usize) {
     // ATTENTION: we have a very small stack and no guard page
     vga_buffer::clear_screen();
     println!("Hello World{}", "!");
 
-    let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
+    // the multiboot information structure is mapped in the higher half, too
+    let boot_info =
+        unsafe { multiboot2::load(memory::KERNEL_OFFSET + multiboot_information_address) };
     enable_nxe_bit();
     enable_write_protect_bit();
 
     // set up guard page and map the heap pages
-    let mut memory_controller = memory::init(boot_info);
+    let memory_controller = memory::init(boot_info);
 
     // initialize our IDT
-    interrupts::init(&mut memory_controller);
+    interrupts::init(&mut memory_controller.lock());
+
+    // switch to a larger stack with a guard page
+    let kernel_stack = memory_controller.lock()
+        .alloc_stack(KERNEL_STACK_PAGES)
+        .expect("could not allocate kernel stack");
+    unsafe { switch_stack(kernel_stack.top(), memory_controller) }
+}
+
+/// Continues with `kernel_main` on the stack that ends at `stack_top`. The current stack is
+/// abandoned.
+unsafe fn switch_stack(stack_top: usize,
+                       memory_controller: &'static Mutex<MemoryController>)
+                       -> ! {
+    asm!("mov rsp, $0
+          xor rbp, rbp // terminate the frame pointer chain for backtraces
+          call $1"
+         :: "r"(stack_top),
+            "r"(kernel_main as extern "C" fn(&'static Mutex<MemoryController>) -> !),
+            "{rdi}"(memory_controller)
+         : "memory" : "intel", "volatile");
+    ::core::intrinsics::unreachable();
+}
+
+extern "C" fn kernel_main(memory_controller: &'static Mutex<MemoryController>) -> ! {
+    // we run on the kernel stack now, so the boot stack can be freed. Its pages stay unmapped,
+    // so stray accesses to it cause a page fault.
+    #[allow(non_upper_case_globals)]
+    extern "C" {
+        static stack_bottom: u8;
+        static stack_top: u8;
+    }
+    unsafe {
+        memory_controller.lock().free_boot_stack(&stack_bottom as *const u8 as usize,
+                                                 &stack_top as *const u8 as usize);
+    }
+
+    run_boot_tests(memory_controller);
+    for region in memory_controller.lock().memory_regions() {
+        println!("memory region {}", region);
+    }
+    println!("{}", memory_controller.lock().stats());
+    for module in memory_controller.lock().modules() {
+        println!("module `{}`: {} bytes", module.cmdline(), module.data().len());
+    }
+    memory::print_heap_report();
 
     fn stack_overflow() {
         stack_overflow(); // for each recursion, the return address is pushed
     }
 
-    // trigger a stack overflow
+    // trigger a stack overflow. It hits the guard page of the kernel stack and is reported as a
+    // page fault.
     stack_overflow();
 
     println!("It did not crash!");
     loop {}
 }
 
+#[cfg(feature = "boot_tests")]
+fn run_boot_tests(memory_controller: &Mutex<MemoryController>) {
+    memory::boot_tests::run(memory_controller);
+}
+
+#[cfg(not(feature = "boot_tests"))]
+fn run_boot_tests(_memory_controller: &Mutex<MemoryController>) {}
+
 fn enable_nxe_bit() {
     use x86::shared::msr::{IA32_EFER, rdmsr, wrmsr};
 
@@ -93,6 +155,7 @@ extern "C" fn eh_personality() {}
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
     println!("\n\nPANIC in {} at line {}:", file, line);
     println!("    {}", fmt);
+    memory::print_heap_report();
     loop {}
 }
 
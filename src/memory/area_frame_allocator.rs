@@ -8,43 +8,97 @@
 // except according to those terms.
 
 use memory::{Frame, FrameAllocator};
+use memory::paging::PhysicalAddress;
 use multiboot2::{MemoryAreaIter, MemoryArea};
 
+/// The maximum number of reserved ranges. We can't use a `Vec` since the allocator is used before
+/// the heap is mapped.
+const MAX_RESERVED_RANGES: usize = 16;
+
+/// The maximum number of freed frames that are kept for reuse. Further freed frames are leaked.
+/// Frames are only freed during boot when a mapping fails or a page table becomes empty, so a few
+/// slots are enough.
+const MAX_FREED_FRAMES: usize = 16;
+
+/// A range of physical frames. Both bounds are frame numbers and _inclusive_.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ReservedRange {
+    fn contains(&self, frame: &Frame) -> bool {
+        frame.number >= self.start && frame.number <= self.end
+    }
+}
+
 /// A frame allocator that uses the memory areas from the multiboot information structure as
-/// source. The {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is
-/// already in use.
+/// source. Frames in reserved ranges (e.g. the kernel and multiboot information structure) are
+/// never returned. All ranges need to be registered through `reserve` before the first frame is
+/// allocated.
 ///
-/// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
+/// Freed frames are kept in a small list and handed out again before any new frame.
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,
     current_area: Option<&'static MemoryArea>,
     areas: MemoryAreaIter,
-    kernel_start: Frame,
-    kernel_end: Frame,
-    multiboot_start: Frame,
-    multiboot_end: Frame,
+    reserved: [ReservedRange; MAX_RESERVED_RANGES],
+    reserved_count: usize,
+    allocation_started: bool,
+    // the numbers of frames that were freed and can be reused
+    freed: [usize; MAX_FREED_FRAMES],
+    freed_count: usize,
 }
 
 impl AreaFrameAllocator {
-    pub fn new(kernel_start: usize,
-               kernel_end: usize,
-               multiboot_start: usize,
-               multiboot_end: usize,
-               memory_areas: MemoryAreaIter)
-               -> AreaFrameAllocator {
+    pub fn new(memory_areas: MemoryAreaIter) -> AreaFrameAllocator {
         let mut allocator = AreaFrameAllocator {
             next_free_frame: Frame::containing_address(0),
             current_area: None,
             areas: memory_areas,
-            kernel_start: Frame::containing_address(kernel_start),
-            kernel_end: Frame::containing_address(kernel_end),
-            multiboot_start: Frame::containing_address(multiboot_start),
-            multiboot_end: Frame::containing_address(multiboot_end),
+            reserved: [ReservedRange { start: 0, end: 0 }; MAX_RESERVED_RANGES],
+            reserved_count: 0,
+            allocation_started: false,
+            freed: [0; MAX_FREED_FRAMES],
+            freed_count: 0,
         };
         allocator.choose_next_area();
         allocator
     }
 
+    /// Excludes the physical memory from `start` to `end` from allocation. `end` is an
+    /// _inclusive_ bound.
+    pub fn reserve(&mut self, start: PhysicalAddress, end: PhysicalAddress) {
+        assert!(!self.allocation_started,
+                "ranges must be reserved before the first allocation");
+        assert!(self.reserved_count < MAX_RESERVED_RANGES,
+                "too many reserved ranges");
+        assert!(start <= end);
+
+        self.reserved[self.reserved_count] = ReservedRange {
+            start: Frame::containing_address(start).number,
+            end: Frame::containing_address(end).number,
+        };
+        self.reserved_count += 1;
+    }
+
+    /// Returns all reserved ranges.
+    pub fn reserved_ranges(&self) -> &[ReservedRange] {
+        &self.reserved[..self.reserved_count]
+    }
+
+    /// Returns the numbers of the frames below `next_free_frame` that were freed again.
+    pub fn freed_frames(&self) -> &[usize] {
+        &self.freed[..self.freed_count]
+    }
+
+    /// Returns the frame that would be considered next. All frames below it are either used,
+    /// freed (see `freed_frames`), or not usable at all.
+    pub fn next_free_frame(&self) -> Frame {
+        self.next_free_frame.clone()
+    }
+
     fn choose_next_area(&mut self) {
         self.current_area = self.areas
             .clone()
@@ -65,6 +119,12 @@ impl AreaFrameAllocator {
 
 impl FrameAllocator for AreaFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocation_started = true;
+
+        if self.freed_count > 0 {
+            self.freed_count -= 1;
+            return Some(Frame { number: self.freed[self.freed_count] });
+        }
         if let Some(area) = self.current_area {
             // "clone" the frame to return it if it's free. Frame doesn't
             // implement Clone, but we can construct an identical frame.
@@ -76,15 +136,17 @@ impl FrameAllocator for AreaFrameAllocator {
                 Frame::containing_address(address as usize)
             };
 
+            let reserved_range = self.reserved_ranges()
+                .iter()
+                .find(|range| range.contains(&frame))
+                .cloned();
+
             if frame > current_area_last_frame {
                 // all frames of current area are used, switch to next area
                 self.choose_next_area();
-            } else if frame >= self.kernel_start && frame <= self.kernel_end {
-                // `frame` is used by the kernel
-                self.next_free_frame = Frame { number: self.kernel_end.number + 1 };
-            } else if frame >= self.multiboot_start && frame <= self.multiboot_end {
-                // `frame` is used by the multiboot information structure
-                self.next_free_frame = Frame { number: self.multiboot_end.number + 1 };
+            } else if let Some(range) = reserved_range {
+                // `frame` is reserved, continue after the reserved range
+                self.next_free_frame = Frame { number: range.end + 1 };
             } else {
                 // frame is unused, increment `next_free_frame` and return it
                 self.next_free_frame.number += 1;
@@ -97,7 +159,12 @@ impl FrameAllocator for AreaFrameAllocator {
         }
     }
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn deallocate_frame(&mut self, frame: Frame) {
+        // if the list is full, the frame is leaked. It stays marked as used in the bitmap
+        // allocator then.
+        if self.freed_count < MAX_FREED_FRAMES {
+            self.freed[self.freed_count] = frame.number;
+            self.freed_count += 1;
+        }
     }
 }
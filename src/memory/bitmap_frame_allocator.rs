@@ -0,0 +1,175 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, AreaFrameAllocator};
+use memory::paging::{self, Page, ActivePageTable, VirtualAllocator, RegionKind};
+use multiboot2::MemoryAreaIter;
+use core::{cmp, slice};
+
+const BITS_PER_WORD: usize = 64;
+
+/// A frame allocator that keeps one bit per physical frame (set = used). In contrast to the
+/// `AreaFrameAllocator`, it supports deallocation.
+///
+/// The bitmap needs one bit per frame, e.g. 128KiB for 4GiB of memory, so it doesn't fit on the
+/// initial heap. Instead, it lives in frames of the boot allocator that are mapped into a region
+/// of the kernel window. The bitmap allocator takes over from the `AreaFrameAllocator` after
+/// that: all frames below the `next_free_frame` of the boot allocator and all of its reserved
+/// ranges are considered used, except for the frames that the boot allocator got back.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    next_word: usize,
+    usable_frames: usize,
+    free_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    pub fn new(memory_areas: MemoryAreaIter,
+               mut boot_allocator: AreaFrameAllocator,
+               active_table: &mut ActivePageTable,
+               virtual_allocator: &mut VirtualAllocator)
+               -> BitmapFrameAllocator {
+        let last_frame = memory_areas.clone()
+            .map(|area| Frame::containing_address((area.base_addr + area.length - 1) as usize))
+            .max()
+            .expect("no usable memory areas");
+        let word_count = last_frame.number / BITS_PER_WORD + 1;
+
+        let page_count = (word_count * BITS_PER_WORD / 8 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let region = virtual_allocator.allocate(page_count, RegionKind::FrameBitmap)
+            .expect("no virtual memory for the frame bitmap");
+        for page in Page::range_inclusive(region.start_page(), region.end_page()) {
+            active_table.map(page, paging::WRITABLE, &mut boot_allocator).expect("out of memory");
+        }
+        let bitmap = unsafe {
+            slice::from_raw_parts_mut(region.start_page().start_address() as *mut u64, word_count)
+        };
+        // start with all frames marked as used and free the usable ones afterwards
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: bitmap,
+            next_word: 0,
+            usable_frames: 0,
+            free_frames: 0,
+        };
+
+        for area in memory_areas {
+            let start_frame = Frame::containing_address(area.base_addr as usize);
+            let end_frame = Frame::containing_address((area.base_addr + area.length - 1) as usize);
+            for frame in Frame::range_inclusive(start_frame, end_frame) {
+                allocator.mark_free(frame);
+            }
+        }
+        // memory areas might overlap, so we count the frames that were actually freed
+        allocator.usable_frames = allocator.free_frames;
+
+        // frames that were handed out by the boot allocator
+        let boot_next_free = boot_allocator.next_free_frame();
+        if boot_next_free.number > 0 {
+            allocator.mark_used_range(Frame { number: 0 },
+                                      Frame { number: boot_next_free.number - 1 });
+        }
+
+        for range in boot_allocator.reserved_ranges() {
+            allocator.mark_used_range(Frame { number: range.start }, Frame { number: range.end });
+        }
+        for &number in boot_allocator.freed_frames() {
+            allocator.mark_free(Frame { number: number });
+        }
+
+        allocator
+    }
+
+    /// Returns the number of frames in the usable memory areas.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Returns the number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocates `count` physically contiguous frames. The first frame is aligned to `count` and
+    /// all frames lie below the frame number `limit`.
+    pub fn allocate_contiguous_frames(&mut self, count: usize, limit: usize) -> Option<Frame> {
+        assert!(count > 0);
+        let end = cmp::min(limit, self.bitmap.len() * BITS_PER_WORD);
+        let mut start = 0;
+        while start + count <= end {
+            // search backwards, so that we can skip past the last used frame of the run
+            let used = (start..start + count).rev().find(|&number| {
+                self.is_used(&Frame { number: number })
+            });
+            match used {
+                Some(number) => start = (number / count + 1) * count,
+                None => {
+                    self.mark_used_range(Frame { number: start },
+                                         Frame { number: start + count - 1 });
+                    return Some(Frame { number: start });
+                }
+            }
+        }
+        None
+    }
+
+    fn is_used(&self, frame: &Frame) -> bool {
+        let (word, bit) = (frame.number / BITS_PER_WORD, frame.number % BITS_PER_WORD);
+        word >= self.bitmap.len() || self.bitmap[word] & (1 << bit) != 0
+    }
+
+    fn mark_free(&mut self, frame: Frame) {
+        let (word, bit) = (frame.number / BITS_PER_WORD, frame.number % BITS_PER_WORD);
+        if self.bitmap[word] & (1 << bit) != 0 {
+            self.bitmap[word] &= !(1 << bit);
+            self.free_frames += 1;
+        }
+    }
+
+    /// Marks the frames in the _inclusive_ range as used. Frames outside of the bitmap are
+    /// ignored.
+    fn mark_used_range(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            if !self.is_used(&frame) {
+                let (word, bit) = (frame.number / BITS_PER_WORD, frame.number % BITS_PER_WORD);
+                self.bitmap[word] |= 1 << bit;
+                self.free_frames -= 1;
+            }
+        }
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let word_count = self.bitmap.len();
+        for i in 0..word_count {
+            let word = (self.next_word + i) % word_count;
+            if self.bitmap[word] != !0 {
+                let bit = (!self.bitmap[word]).trailing_zeros() as usize;
+                self.bitmap[word] |= 1 << bit;
+                self.free_frames -= 1;
+                // all words before `word` are full, so the next search can start here
+                self.next_word = word;
+                return Some(Frame { number: word * BITS_PER_WORD + bit });
+            }
+        }
+        None // no free frames left
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(frame.number / BITS_PER_WORD < self.bitmap.len() && self.is_used(&frame),
+                "frame {:#x} is not allocated",
+                frame.start_address());
+        self.next_word = cmp::min(self.next_word, frame.number / BITS_PER_WORD);
+        self.mark_free(frame);
+    }
+}
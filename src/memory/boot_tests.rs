@@ -0,0 +1,394 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Self-tests of the memory subsystem. They run at boot when the kernel is built with the
+//! `boot_tests` feature.
+
+use memory::{PAGE_SIZE, KERNEL_OFFSET, MemoryController, CacheMode, Frame, FrameAllocator};
+use memory::paging::{self, Page, ActivePageTable, EntryFlags};
+use memory::shared_frames::SharingAllocator;
+use hole_list_allocator::{self, HEAP_START};
+use collections::Vec;
+use spin::Mutex;
+
+/// Runs all tests. The memory controller must not be locked by the caller.
+pub fn run(memory_controller: &Mutex<MemoryController>) {
+    test_paging(&mut memory_controller.lock());
+    test_stacks(&mut memory_controller.lock());
+    test_mmio(&mut memory_controller.lock());
+    test_errors(&mut memory_controller.lock());
+    test_update_flags(&mut memory_controller.lock());
+    test_copy_on_write(memory_controller);
+    test_lazy_heap(memory_controller);
+    test_dump(&mut memory_controller.lock());
+    memory_controller.lock().dump_page_tables();
+}
+
+/// Returns the first page of P4 entry 42, which is unused and 1GiB aligned. The tests map their
+/// pages there.
+fn test_page() -> Page {
+    Page::containing_address(42 * 512 * 512 * 512 * PAGE_SIZE)
+}
+
+/// Returns the flags of the given page, as reported by the page table walker.
+fn flags_of(active_table: &ActivePageTable, page: Page) -> Option<EntryFlags> {
+    let mut flags = None;
+    active_table.for_each_mapping(|mapping| {
+        if mapping.start <= page.start_address() && page.start_address() <= mapping.end {
+            flags = Some(mapping.flags);
+        }
+    });
+    flags
+}
+
+/// A frame allocator that fails after the given number of frames. Freed frames go back to the
+/// wrapped allocator.
+struct LimitedAllocator<'a, A: 'a> {
+    allocator: &'a mut A,
+    frames_left: usize,
+}
+
+impl<'a, A: FrameAllocator> FrameAllocator for LimitedAllocator<'a, A> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.frames_left == 0 {
+            return None;
+        }
+        self.frames_left -= 1;
+        self.allocator.allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.allocator.deallocate_frame(frame)
+    }
+}
+
+/// Maps and unmaps a page in an unused P4 entry multiple times and checks that neither the
+/// mapped frame nor the created page tables are leaked.
+fn test_paging(memory_controller: &mut MemoryController) {
+    let &mut MemoryController { ref mut active_table, ref mut frame_allocator, .. } =
+        memory_controller;
+
+    let page = test_page();
+    let free_frames = frame_allocator.free_frames();
+
+    for _ in 0..10 {
+        active_table.map(page, paging::WRITABLE, frame_allocator).expect("mapping failed");
+        // one frame for the page and one for each of the P3, P2 and P1 tables
+        assert_eq!(frame_allocator.free_frames(), free_frames - 4);
+
+        let frame = active_table.unmap(page, frame_allocator).expect("unmapping failed");
+        frame_allocator.deallocate_frame(frame);
+        assert_eq!(frame_allocator.free_frames(), free_frames);
+    }
+    println!("test_paging: no frames leaked");
+}
+
+/// Allocates and frees stacks of different sizes and checks that their pages and frames are
+/// reused.
+fn test_stacks(memory_controller: &mut MemoryController) {
+    let free_frames = memory_controller.frame_allocator.free_frames();
+
+    let stack = memory_controller.alloc_stack(4).expect("stack allocation failed");
+    let bottom = stack.bottom();
+    memory_controller.free_stack(stack);
+
+    // two smaller stacks (with their guard pages) fit into the freed hole
+    let first = memory_controller.alloc_stack(1).expect("stack allocation failed");
+    let second = memory_controller.alloc_stack(2).expect("stack allocation failed");
+    assert_eq!(first.bottom(), bottom);
+    assert_eq!(second.bottom(), first.top() + PAGE_SIZE);
+    memory_controller.free_stack(first);
+    memory_controller.free_stack(second);
+
+    assert_eq!(memory_controller.frame_allocator.free_frames(), free_frames);
+    println!("test_stacks: stacks reused, no frames leaked");
+}
+
+/// Triggers the errors of the mapping functions and checks that the failed operations undo
+/// their changes.
+fn test_errors(memory_controller: &mut MemoryController) {
+    use memory::mmio;
+    use memory::paging::MapError;
+
+    let free_frames = memory_controller.frame_allocator.free_frames();
+    let page_tables = memory_controller.active_table.page_table_count();
+    let regions = memory_controller.virtual_allocator.regions().len();
+    let page = test_page();
+
+    {
+        let &mut MemoryController { ref mut active_table, ref mut frame_allocator, .. } =
+            memory_controller;
+        for page in Page::range_inclusive(page, page + 1) {
+            active_table.map(page, paging::WRITABLE, frame_allocator).expect("mapping failed");
+        }
+        assert_eq!(active_table.map(page, paging::WRITABLE, frame_allocator),
+                   Err(MapError::AlreadyMapped));
+        // the 2MiB page at `page` is mapped through a P1 table already
+        assert_eq!(active_table.map_to_2mib(page,
+                                            Frame::containing_address(0),
+                                            paging::WRITABLE,
+                                            frame_allocator),
+                   Err(MapError::HugePageConflict));
+    }
+
+    // sharing with a mapped target fails and leaves the page writable
+    assert_eq!(memory_controller.share_copy_on_write(page, page + 1),
+               Err(MapError::AlreadyMapped));
+    let flags = flags_of(&memory_controller.active_table, page).unwrap();
+    assert!(flags.contains(paging::WRITABLE) && !flags.contains(paging::COPY_ON_WRITE));
+
+    {
+        let &mut MemoryController { ref mut active_table,
+                                    ref mut frame_allocator,
+                                    ref mut virtual_allocator,
+                                    ref mut stack_allocator,
+                                    ref memory_regions,
+                                    .. } = memory_controller;
+        for page in Page::range_inclusive(page, page + 1) {
+            let frame = active_table.unmap(page, frame_allocator).expect("unmapping failed");
+            frame_allocator.deallocate_frame(frame);
+        }
+
+        // device memory that spans two P1 tables needs a new one, which fails without frames.
+        // The pages that were mapped already are unmapped again.
+        let mut allocator = LimitedAllocator {
+            allocator: &mut *frame_allocator,
+            frames_left: 0,
+        };
+        assert!(mmio::map_mmio(active_table,
+                               &mut allocator,
+                               virtual_allocator,
+                               memory_regions,
+                               0xfd00_0000,
+                               512 * PAGE_SIZE + PAGE_SIZE,
+                               CacheMode::Uncached)
+            .is_none());
+
+        // a stack runs out of frames after two of its four pages
+        let mut allocator = LimitedAllocator {
+            allocator: &mut *frame_allocator,
+            frames_left: 2,
+        };
+        let mapped_pages = stack_allocator.mapped_pages();
+        assert!(stack_allocator.alloc_stack(active_table, &mut allocator, virtual_allocator, 4)
+            .is_none());
+        assert_eq!(stack_allocator.mapped_pages(), mapped_pages);
+    }
+
+    assert_eq!(memory_controller.frame_allocator.free_frames(), free_frames);
+    assert_eq!(memory_controller.active_table.page_table_count(), page_tables);
+    assert_eq!(memory_controller.virtual_allocator.regions().len(), regions);
+    println!("test_errors: failed operations rolled back");
+}
+
+/// Changes the flags of a single page in a 2MiB page and checks that the 2MiB page is split.
+/// Restoring the flags allows merging the pages again.
+fn test_update_flags(memory_controller: &mut MemoryController) {
+    let page = test_page();
+    let frame = memory_controller.allocate_frames(9, None).expect("no 2MiB block available");
+    let frame_address = frame.start_address();
+    let page_tables = memory_controller.active_table.page_table_count();
+    memory_controller.active_table
+        .map_to_2mib(page, frame, paging::WRITABLE, &mut memory_controller.frame_allocator)
+        .expect("mapping failed");
+
+    // making a single page read-only splits the 2MiB page
+    memory_controller.protect((page + 1).start_address(), PAGE_SIZE, EntryFlags::empty())
+        .expect("protect failed");
+    assert_eq!(memory_controller.active_table.page_table_count(), page_tables + 3);
+    assert!(flags_of(&memory_controller.active_table, page).unwrap().contains(paging::WRITABLE));
+    assert!(!flags_of(&memory_controller.active_table, page + 1)
+        .unwrap()
+        .contains(paging::WRITABLE));
+    assert_eq!(memory_controller.active_table.translate((page + 1).start_address()),
+               Some(frame_address + PAGE_SIZE));
+
+    // with identical flags, the pages can be merged again
+    memory_controller.protect(page.start_address(), 512 * PAGE_SIZE, paging::WRITABLE)
+        .expect("protect failed");
+    assert!(memory_controller.active_table
+        .collapse_2mib(page, &mut memory_controller.frame_allocator));
+    let frame = memory_controller.active_table
+        .unmap_2mib(page, &mut memory_controller.frame_allocator)
+        .expect("unmapping failed");
+    memory_controller.deallocate_frames(frame, 9);
+
+    assert_eq!(memory_controller.active_table.page_table_count(), page_tables);
+    println!("test_update_flags: huge page split and merged again");
+}
+
+/// Maps the VGA text buffer as device memory and checks that writes through the uncached mapping
+/// show up in the regular VGA buffer mapping.
+///
+/// Mixing memory types for a frame is undefined, so the write-back mapping of the VGA buffer is
+/// removed while the uncached mapping exists. Nothing can be printed in the meantime.
+fn test_mmio(memory_controller: &mut MemoryController) {
+    use core::ptr::{read_volatile, write_volatile};
+
+    let free_frames = memory_controller.frame_allocator.free_frames();
+    let vga_buffer = (KERNEL_OFFSET + 0xb8000) as *const u16;
+    let vga_page = Page::containing_address(vga_buffer as usize);
+    let vga_flags = flags_of(&memory_controller.active_table, vga_page).unwrap();
+    let character = unsafe { read_volatile(vga_buffer) };
+
+    // failed asserts print, so the results are only checked when the VGA buffer is mapped again
+    let vga_frame = memory_controller.active_table
+        .unmap(vga_page, &mut memory_controller.frame_allocator)
+        .expect("VGA buffer is not mapped");
+    let mut mmio = memory_controller.map_mmio(0xb8000, 80 * 25 * 2, CacheMode::Uncached)
+        .expect("mmio mapping failed");
+    let read = mmio.read::<u16>(0);
+    mmio.write::<u16>(0, character ^ 0xff);
+    let read_after_write = mmio.read::<u16>(0);
+
+    // changing the protection keeps the cache mode
+    let page = Page::containing_address(mmio.start_address());
+    let protected = memory_controller.protect(page.start_address(), PAGE_SIZE, paging::NO_EXECUTE);
+    let flags = flags_of(&memory_controller.active_table, page).unwrap();
+    memory_controller.unmap_mmio(mmio);
+
+    memory_controller.active_table
+        .map_to(vga_page,
+                vga_frame,
+                vga_flags - paging::PRESENT,
+                &mut memory_controller.frame_allocator)
+        .expect("failed to map the VGA buffer again");
+    assert_eq!(read, character);
+    assert_eq!(read_after_write, character ^ 0xff);
+    assert!(protected.is_ok());
+    assert!(flags.contains(paging::NO_CACHE | paging::WRITE_THROUGH));
+    assert!(!flags.contains(paging::WRITABLE));
+
+    // the write is visible through the write-back mapping
+    assert_eq!(unsafe { read_volatile(vga_buffer) }, character ^ 0xff);
+    unsafe { write_volatile(vga_buffer as *mut u16, character) };
+
+    assert_eq!(memory_controller.frame_allocator.free_frames(), free_frames);
+
+    // RAM, e.g. a frame of the heap, is not device memory
+    let heap_frame = memory_controller.active_table.translate(HEAP_START).unwrap();
+    assert!(memory_controller.map_mmio(heap_frame, PAGE_SIZE, CacheMode::Uncached).is_none());
+    println!("test_mmio: device memory mapped and unmapped");
+}
+
+/// Shares a page copy-on-write and checks that writes to either page don't affect the other. The
+/// writes cause page faults, so the memory controller must not be locked while they happen.
+fn test_copy_on_write(memory_controller: &Mutex<MemoryController>) {
+    use core::ptr::{read_volatile, write_volatile};
+
+    let page = test_page();
+    let target = page + 1;
+    let original = page.start_address() as *mut u64;
+    let copy = target.start_address() as *mut u64;
+
+    {
+        let mut memory_controller = memory_controller.lock();
+        let MemoryController { ref mut active_table, ref mut frame_allocator, .. } =
+            *memory_controller;
+        active_table.map(page, paging::WRITABLE, frame_allocator).expect("mapping failed");
+    }
+    unsafe { write_volatile(original, 42) };
+    memory_controller.lock().share_copy_on_write(page, target).expect("sharing failed");
+
+    unsafe {
+        assert_eq!(read_volatile(copy), 42);
+        write_volatile(copy, 43); // copies the frame
+        write_volatile(original, 44); // the frame isn't shared anymore, so it's just made writable
+        assert_eq!(read_volatile(copy), 43);
+        assert_eq!(read_volatile(original), 44);
+    }
+
+    let mut memory_controller = memory_controller.lock();
+    unmap_shared(&mut memory_controller, target);
+
+    // a read-only page stays read-only, so a write to it is a protection fault
+    memory_controller.protect(page.start_address(), PAGE_SIZE, paging::EntryFlags::empty())
+        .expect("protecting failed");
+    memory_controller.share_copy_on_write(page, target).expect("sharing failed");
+    assert!(!memory_controller.active_table.is_copy_on_write(target));
+
+    // making a shared page writable takes effect when it is copied on the first write
+    memory_controller.protect(target.start_address(), PAGE_SIZE, paging::WRITABLE)
+        .expect("protecting failed");
+    assert!(memory_controller.active_table.is_copy_on_write(target));
+    assert!(!flags_of(&memory_controller.active_table, target)
+        .unwrap()
+        .contains(paging::WRITABLE));
+
+    // unmapping a shared page only drops a reference, the other page still uses the frame
+    let free_frames = memory_controller.frame_allocator.free_frames();
+    unmap_shared(&mut memory_controller, target);
+    assert_eq!(memory_controller.frame_allocator.free_frames(), free_frames);
+    unmap_shared(&mut memory_controller, page);
+    assert!(memory_controller.frame_allocator.free_frames() > free_frames);
+    println!("test_copy_on_write: pages copied on write");
+}
+
+/// Unmaps the given page and frees its frame unless other pages still share it.
+fn unmap_shared(memory_controller: &mut MemoryController, page: Page) {
+    let MemoryController { ref mut active_table,
+                           ref mut frame_allocator,
+                           ref mut shared_frames,
+                           .. } = *memory_controller;
+    let mut frame_allocator = SharingAllocator {
+        allocator: frame_allocator,
+        shared_frames: shared_frames,
+    };
+    let frame = active_table.unmap(page, &mut frame_allocator).expect("unmapping failed");
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Grows the heap and checks that its new pages are backed on first access.
+fn test_lazy_heap(memory_controller: &Mutex<MemoryController>) {
+    let heap_size = hole_list_allocator::heap_size();
+
+    // the memory controller is locked while the heap grows, so the pages come from the reserve
+    let mut memory_controller = memory_controller.lock();
+    let mut data = Vec::with_capacity(heap_size);
+    let new_page = Page::containing_address(HEAP_START + heap_size);
+    assert!(hole_list_allocator::heap_size() > heap_size);
+    assert!(data.as_ptr() as usize + heap_size > new_page.start_address());
+
+    for i in 0..heap_size {
+        data.push(i as u8);
+    }
+    assert!(memory_controller.active_table.translate_page(new_page).is_some());
+    assert!(data.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+    drop(data);
+
+    // the page behind the heap is not backed
+    let behind_heap = Page::containing_address(HEAP_START + hole_list_allocator::heap_size());
+    assert!(memory_controller.active_table.translate_page(behind_heap).is_none());
+    println!("test_lazy_heap: heap pages backed on first access");
+}
+
+/// Maps a page and checks the dump output of its mapping.
+fn test_dump(memory_controller: &mut MemoryController) {
+    let &mut MemoryController { ref mut active_table, ref mut frame_allocator, .. } =
+        memory_controller;
+
+    let page = test_page();
+    active_table.map(page, paging::WRITABLE | paging::NO_EXECUTE, frame_allocator)
+        .expect("mapping failed");
+    let frame = active_table.translate_page(page).unwrap();
+
+    let mut output = None;
+    active_table.for_each_mapping(|mapping| if mapping.start == page.start_address() {
+        output = Some(format!("{}", mapping));
+    });
+    assert_eq!(output.unwrap(),
+               format!("{:#x}-{:#x} -> phys {:#x} RW-",
+                       page.start_address(),
+                       page.start_address() + PAGE_SIZE,
+                       frame.start_address()));
+
+    let frame = active_table.unmap(page, frame_allocator).expect("unmapping failed");
+    frame_allocator.deallocate_frame(frame);
+    println!("test_dump: mapping formatted");
+}
@@ -0,0 +1,113 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, BitmapFrameAllocator};
+use memory::paging::PhysicalAddress;
+use collections::{Vec, BTreeSet};
+use core::cmp;
+
+/// The largest supported order. A block of order `n` consists of `2^n` frames, so the largest
+/// block is 4MiB.
+pub const MAX_ORDER: usize = 10;
+
+/// Physical addresses below this limit can be reached by devices that only support 32-bit DMA.
+pub const LIMIT_4GIB: PhysicalAddress = 0x1_0000_0000;
+
+/// A buddy allocator for physically contiguous blocks of `2^order` frames. A block of order `n`
+/// always starts at a frame number that is a multiple of `2^n`.
+///
+/// The allocator does not own any memory at creation. Instead, it takes blocks of `MAX_ORDER`
+/// from the `BitmapFrameAllocator` when it runs out and hands them back as soon as they are
+/// completely free again.
+pub struct BuddyFrameAllocator {
+    // the free blocks of each order, identified by the number of their first frame
+    free_lists: Vec<BTreeSet<usize>>,
+}
+
+impl BuddyFrameAllocator {
+    pub fn new() -> BuddyFrameAllocator {
+        BuddyFrameAllocator { free_lists: (0..MAX_ORDER + 1).map(|_| BTreeSet::new()).collect() }
+    }
+
+    /// Allocates `2^order` contiguous frames and returns the first one. If `limit` is given, the
+    /// whole block lies below that physical address.
+    pub fn allocate_frames(&mut self,
+                           order: usize,
+                           limit: Option<PhysicalAddress>,
+                           frame_allocator: &mut BitmapFrameAllocator)
+                           -> Option<Frame> {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        let limit_frame = limit.map(|limit| limit / PAGE_SIZE).unwrap_or(usize::max_value());
+
+        // the free lists are sorted, so we only need to look at the first block of each order
+        let block = (order..MAX_ORDER + 1)
+            .filter_map(|block_order| {
+                self.free_lists[block_order]
+                    .iter()
+                    .next()
+                    .map(|&number| (number, block_order))
+            })
+            .find(|&(number, _)| number + (1 << order) <= limit_frame);
+
+        let (number, block_order) = match block {
+            Some((number, block_order)) => {
+                self.free_lists[block_order].remove(&number);
+                (number, block_order)
+            }
+            None => {
+                let count = 1 << MAX_ORDER;
+                let frame = match frame_allocator.allocate_contiguous_frames(count, limit_frame) {
+                    Some(frame) => frame,
+                    None => return None,
+                };
+                (frame.number, MAX_ORDER)
+            }
+        };
+
+        // split the block and put the unused upper halves on the free lists
+        for split_order in (order..block_order).rev() {
+            self.free_lists[split_order].insert(number + (1 << split_order));
+        }
+        Some(Frame { number: number })
+    }
+
+    /// Frees the block of `2^order` frames that starts at `frame`. The block is merged with its
+    /// buddy as long as the buddy is free, too.
+    pub fn deallocate_frames(&mut self,
+                             frame: Frame,
+                             order: usize,
+                             frame_allocator: &mut BitmapFrameAllocator) {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        assert!(frame.number % (1 << order) == 0,
+                "frame {:#x} is not aligned to order {}",
+                frame.start_address(),
+                order);
+
+        let mut number = frame.number;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            number = cmp::min(number, buddy);
+            order += 1;
+        }
+
+        if order == MAX_ORDER {
+            // the whole block is free again, so we give it back to the frame allocator
+            let end = Frame { number: number + (1 << MAX_ORDER) - 1 };
+            for frame in Frame::range_inclusive(Frame { number: number }, end) {
+                frame_allocator.deallocate_frame(frame);
+            }
+        } else {
+            self.free_lists[order].insert(number);
+        }
+    }
+}
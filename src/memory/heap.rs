@@ -0,0 +1,171 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::paging::{self, Page, ActivePageTable, VirtualAddress, VirtualRegion, RegionKind};
+use hole_list_allocator::{HEAP_START, HEAP_SIZE};
+use spin::Mutex;
+
+/// The number of frames that are set aside for growing the heap. A single growth can't be larger
+/// than this (1MiB).
+const RESERVE_FRAMES: usize = 256;
+
+/// The amount of memory that a single P1 table maps.
+const P1_TABLE_SIZE: usize = 512 * PAGE_SIZE;
+
+/// Frames that are set aside for backing the kernel heap.
+///
+/// The heap grows when an allocation doesn't fit, which might happen while the memory controller
+/// is locked (e.g. when the memory controller pushes to a `Vec`). The new heap pages are mapped on
+/// first access by the page fault handler, which might interrupt code that holds the memory
+/// controller as well. So the heap can't rely on the frame allocator of the memory controller.
+/// Instead, it takes its frames from this reserve, which has its own lock and is refilled
+/// whenever the memory controller is available.
+struct HeapReserve {
+    // the numbers of the reserved frames
+    frames: [usize; RESERVE_FRAMES],
+    count: usize,
+    // the number of reserved frames that are promised to heap pages that weren't accessed yet
+    pending: usize,
+    lazy_region: Option<LazyRegion>,
+}
+
+/// A virtual region whose pages are mapped to zeroed frames on first access.
+struct LazyRegion {
+    region: VirtualRegion,
+    flags: paging::EntryFlags,
+    // only pages below this address are backed, accesses behind it are bugs
+    end: VirtualAddress,
+}
+
+static HEAP_RESERVE: Mutex<HeapReserve> = Mutex::new(HeapReserve {
+    frames: [0; RESERVE_FRAMES],
+    count: 0,
+    pending: 0,
+    lazy_region: None,
+});
+
+/// Creates the page tables for the whole heap region, so that backing a heap page only changes
+/// its P1 entry. The memory controller never maps pages in the heap region, so heap pages can be
+/// mapped while it is locked. Then fills the reserve.
+///
+/// The initial heap must already be mapped, the rest of the region is backed on demand.
+pub fn init<A>(region: VirtualRegion, active_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    assert!(region.kind() == RegionKind::Heap);
+    assert!(region.start_page() == Page::containing_address(HEAP_START));
+
+    let mut page = region.start_page();
+    while region.contains(page) {
+        active_table.create_tables(page, allocator).expect("out of memory");
+        page = page + P1_TABLE_SIZE / PAGE_SIZE;
+    }
+    HEAP_RESERVE.lock().lazy_region = Some(LazyRegion {
+        region: region,
+        flags: paging::WRITABLE,
+        end: HEAP_START + HEAP_SIZE,
+    });
+    refill(allocator);
+}
+
+/// Tops up the reserve with frames from the given allocator.
+pub fn refill<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut reserve = HEAP_RESERVE.lock();
+    while reserve.count < RESERVE_FRAMES {
+        match allocator.allocate_frame() {
+            Some(frame) => reserve.deallocate_frame(frame),
+            None => break,
+        }
+    }
+}
+
+/// Lets the heap grow into `[start, start + size)`, which must directly follow the current heap.
+/// The pages are backed on first access, see `map_lazy_page`. Returns `false` if the reserve
+/// doesn't contain enough frames for them.
+pub fn grow(start: VirtualAddress, size: usize) -> bool {
+    let mut reserve = HEAP_RESERVE.lock();
+    let page_count = size / PAGE_SIZE;
+    if page_count == 0 || reserve.count - reserve.pending < page_count {
+        return false;
+    }
+    let end = start + page_count * PAGE_SIZE;
+    match reserve.lazy_region {
+        Some(ref mut lazy_region) => {
+            assert!(start == lazy_region.end, "the heap must grow at its end");
+            if !lazy_region.region.contains(Page::containing_address(end - 1)) {
+                return false;
+            }
+            lazy_region.end = end;
+        }
+        None => return false,
+    }
+    reserve.pending += page_count;
+    true
+}
+
+/// Maps the page that contains the given address to a zeroed frame of the reserve if it's a heap
+/// page that wasn't accessed yet. Returns `false` if the fault can't be resolved this way.
+///
+/// Called by the page fault handler for faults on non-present pages, so it must not allocate heap
+/// memory. It doesn't need the memory controller.
+pub fn map_lazy_page(address: VirtualAddress) -> bool {
+    use core::ptr;
+
+    // the fault might interrupt `grow` or `refill`
+    let mut reserve = match HEAP_RESERVE.try_lock() {
+        Some(reserve) => reserve,
+        None => return false,
+    };
+    let page = Page::containing_address(address);
+    let flags = match reserve.lazy_region {
+        Some(ref lazy_region) if lazy_region.region.contains(page) &&
+                                 address < lazy_region.end => lazy_region.flags,
+        _ => return false,
+    };
+
+    // the page tables exist, so only the P1 entry of the page changes. Fails if the recursive
+    // entry points to an inactive table right now.
+    let mapped = unsafe {
+        paging::with_active_mapper(|mapper| {
+            // the fault might be caused by a mapped page, e.g. by executing a no-execute page
+            if mapper.translate_page(page).is_some() {
+                return false;
+            }
+            mapper.map(page, flags, &mut *reserve).expect("heap page tables are missing");
+            true
+        })
+    };
+    if mapped != Some(true) {
+        return false;
+    }
+    reserve.pending -= 1;
+
+    // heap pages are writable, so we can zero the frame through the new mapping
+    unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE) };
+    true
+}
+
+impl FrameAllocator for HeapReserve {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(Frame { number: self.frames[self.count] })
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(self.count < RESERVE_FRAMES, "the heap reserve is full");
+        self.frames[self.count] = frame.number;
+        self.count += 1;
+    }
+}
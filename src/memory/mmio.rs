@@ -0,0 +1,203 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Mappings of device memory, e.g. framebuffers and PCI BARs.
+//!
+//! The caching behavior of a page is selected by the `WRITE_THROUGH`, `NO_CACHE`, and `PAT` bits
+//! of its entry, which form an index into the page attribute table (PAT). We keep the first four
+//! entries of the PAT at their power-on defaults, so that pages without the `PAT` bit behave as
+//! before, and use the fifth entry for write-combining.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, MemoryRegion, MemoryAreaType};
+use memory::paging::{self, Page, ActivePageTable, EntryFlags, PhysicalAddress, VirtualAddress,
+                     VirtualAllocator, VirtualRegion, RegionKind};
+use core::{mem, ptr};
+use spin::Once;
+
+const IA32_PAT: u32 = 0x277;
+
+// the PAT memory types
+const WRITE_BACK: u64 = 0x06;
+const WRITE_THROUGH: u64 = 0x04;
+const UNCACHED_MINUS: u64 = 0x07;
+const UNCACHED: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+
+static PAT_SUPPORTED: Once<bool> = Once::new();
+
+/// How the CPU caches accesses to a device mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device in program order. Use this for device registers.
+    Uncached,
+    /// Writes are buffered and combined into bursts, reads are uncached. Use this for
+    /// framebuffers.
+    WriteCombining,
+    /// Reads are cached, writes go to the device immediately.
+    WriteThrough,
+}
+
+impl CacheMode {
+    /// Returns the entry flags that select the cache mode in a P1 entry.
+    fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheMode::Uncached => paging::NO_CACHE | paging::WRITE_THROUGH,
+            CacheMode::WriteCombining if pat_supported() => paging::PAT,
+            // without PAT, the PAT bit is reserved, so we fall back to uncached
+            CacheMode::WriteCombining => paging::NO_CACHE | paging::WRITE_THROUGH,
+            CacheMode::WriteThrough => paging::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Programs the PAT if the CPU supports it. Only the unused fifth entry changes, so existing
+/// mappings are not affected.
+pub fn init_pat() {
+    use x86::shared::msr::wrmsr;
+    use x86::shared::tlb;
+
+    PAT_SUPPORTED.call_once(|| {
+        let (_, _, _, edx) = super::cpuid(1, 0);
+        if edx & (1 << 16) == 0 {
+            return false;
+        }
+        let pat = [WRITE_BACK,
+                   WRITE_THROUGH,
+                   UNCACHED_MINUS,
+                   UNCACHED,
+                   WRITE_COMBINING,
+                   WRITE_THROUGH,
+                   UNCACHED_MINUS,
+                   UNCACHED];
+        let value = pat.iter().enumerate().fold(0, |value, (i, &memory_type)| {
+            value | memory_type << (i * 8)
+        });
+        // the SDM requires flushing the caches and the TLB around a change of the PAT, so that
+        // no cache lines or translations with the old memory types remain
+        unsafe {
+            asm!("wbinvd" ::: "memory" : "volatile");
+            wrmsr(IA32_PAT, value);
+            asm!("wbinvd" ::: "memory" : "volatile");
+            tlb::flush_all();
+        }
+        true
+    });
+}
+
+fn pat_supported() -> bool {
+    *PAT_SUPPORTED.try().unwrap_or(&false)
+}
+
+/// Maps the `size` bytes of device memory at `physical_address` with the given cache mode.
+/// Returns `None` if there is not enough virtual memory or no frames for the page tables.
+///
+/// Ranges that overlap available RAM are rejected, since the frames might be in use with a
+/// different memory type (and mixing memory types for a frame is undefined).
+pub fn map_mmio<FA: FrameAllocator>(active_table: &mut ActivePageTable,
+                                    frame_allocator: &mut FA,
+                                    virtual_allocator: &mut VirtualAllocator,
+                                    memory_regions: &[MemoryRegion],
+                                    physical_address: PhysicalAddress,
+                                    size: usize,
+                                    cache_mode: CacheMode)
+                                    -> Option<Mmio> {
+    if size == 0 {
+        return None;
+    }
+    let is_ram = |region: &MemoryRegion| {
+        region.typ == MemoryAreaType::Available &&
+        region.start_address < physical_address + size &&
+        physical_address < region.start_address + region.size
+    };
+    if memory_regions.iter().any(is_ram) {
+        return None;
+    }
+    let start_frame = Frame::containing_address(physical_address);
+    let end_frame = Frame::containing_address(physical_address + size - 1);
+    let page_count = end_frame.number - start_frame.number + 1;
+
+    let region = match virtual_allocator.allocate(page_count, RegionKind::Mmio) {
+        Some(region) => region,
+        None => return None,
+    };
+    let flags = paging::WRITABLE | paging::NO_EXECUTE | cache_mode.flags();
+    let pages = Page::range_inclusive(region.start_page(), region.end_page());
+    for (page, frame) in pages.zip(Frame::range_inclusive(start_frame, end_frame)) {
+        if active_table.map_to(page, frame, flags, frame_allocator).is_err() {
+            // not enough frames for the page tables, undo the mappings of the previous pages
+            let pages = Page::range_inclusive(region.start_page(), region.end_page());
+            for mapped in pages.take_while(|&p| p < page) {
+                active_table.unmap(mapped, frame_allocator).unwrap();
+            }
+            virtual_allocator.free(region);
+            return None;
+        }
+    }
+
+    Some(Mmio {
+        start: region.start_page().start_address() + physical_address % PAGE_SIZE,
+        size: size,
+        region: region,
+    })
+}
+
+/// Unmaps the given device memory. The frames belong to the device, so they are not freed.
+pub fn unmap_mmio<FA: FrameAllocator>(mmio: Mmio,
+                                      active_table: &mut ActivePageTable,
+                                      frame_allocator: &mut FA,
+                                      virtual_allocator: &mut VirtualAllocator) {
+    for page in Page::range_inclusive(mmio.region.start_page(), mmio.region.end_page()) {
+        active_table.unmap(page, frame_allocator).expect("device memory is not mapped");
+    }
+    virtual_allocator.free(mmio.region);
+}
+
+/// A mapping of device memory with volatile accessors for its registers.
+#[derive(Debug)]
+pub struct Mmio {
+    start: VirtualAddress,
+    size: usize,
+    region: VirtualRegion,
+}
+
+impl Mmio {
+    /// Returns the virtual address of the first byte of device memory.
+    #[allow(dead_code)]
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    #[allow(dead_code)]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the register of type `T` at the given byte offset.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.register(offset)) }
+    }
+
+    /// Writes `value` to the register of type `T` at the given byte offset.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.register(offset), value) }
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + mem::size_of::<T>() <= self.size,
+                "register at offset {:#x} is outside of the mapping",
+                offset);
+        let address = self.start + offset;
+        assert!(address % mem::align_of::<T>() == 0,
+                "register at offset {:#x} is not aligned",
+                offset);
+        address as *mut T
+    }
+}
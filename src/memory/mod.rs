@@ -8,31 +8,63 @@
 // except according to those terms.
 
 pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::buddy_frame_allocator::{BuddyFrameAllocator, MAX_ORDER, LIMIT_4GIB};
 pub use self::paging::remap_the_kernel;
 pub use self::stack_allocator::Stack;
-use self::paging::PhysicalAddress;
+pub use self::mmio::{Mmio, CacheMode};
+pub use self::modules::Module;
+pub use self::stats::{MemoryStats, MemoryRegion, MemoryAreaType};
+use self::paging::{PhysicalAddress, VirtualAddress};
+use self::shared_frames::{SharedFrames, SharingAllocator};
 use multiboot2::BootInformation;
+use collections::Vec;
+use spin::{Mutex, Once};
 
 mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod buddy_frame_allocator;
+mod heap;
+mod mmio;
+mod modules;
 mod paging;
+mod shared_frames;
 mod stack_allocator;
+mod stats;
+#[cfg(feature = "boot_tests")]
+pub mod boot_tests;
 
 pub const PAGE_SIZE: usize = 4096;
 
-pub fn init(boot_info: &BootInformation) -> MemoryController {
+/// The kernel is linked at this offset from its physical address (see `linker.ld`). The multiboot
+/// information structure and the VGA buffer are mapped at the same offset.
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+
+static MEMORY_CONTROLLER: Once<Mutex<MemoryController>> = Once::new();
+
+pub fn init(boot_info: &BootInformation) -> &'static Mutex<MemoryController> {
     assert_has_not_been_called!("memory::init must be called only once");
 
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
     let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf sections tag required");
 
+    // the boot code is linked at its physical address, all other sections at `KERNEL_OFFSET`
+    let physical_address = |address: u64| {
+        let address = address as usize;
+        if address >= KERNEL_OFFSET {
+            address - KERNEL_OFFSET
+        } else {
+            address
+        }
+    };
     let kernel_start = elf_sections_tag.sections()
         .filter(|s| s.is_allocated())
-        .map(|s| s.addr)
+        .map(|s| physical_address(s.addr))
         .min()
         .unwrap();
     let kernel_end = elf_sections_tag.sections()
         .filter(|s| s.is_allocated())
-        .map(|s| s.addr + s.size)
+        .map(|s| physical_address(s.addr + s.size))
         .max()
         .unwrap();
 
@@ -43,50 +75,404 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
              boot_info.start_address(),
              boot_info.end_address());
 
-    let mut frame_allocator = AreaFrameAllocator::new(kernel_start as usize,
-                                                      kernel_end as usize,
-                                                      boot_info.start_address(),
-                                                      boot_info.end_address(),
-                                                      memory_map_tag.memory_areas());
+    let mut boot_allocator = AreaFrameAllocator::new(memory_map_tag.memory_areas());
+    // the boot page tables and the boot stack are part of the kernel's .bss section. ACPI tables
+    // and memory mapped devices lie in areas that are not available, so we never allocate them.
+    boot_allocator.reserve(kernel_start, kernel_end);
+    boot_allocator.reserve(boot_info.start_address() - KERNEL_OFFSET,
+                           boot_info.end_address() - KERNEL_OFFSET);
+    // frame 0 contains the real mode IVT and the BIOS data area
+    boot_allocator.reserve(0, PAGE_SIZE - 1);
+    // the VGA text buffer
+    boot_allocator.reserve(0xb8000, 0xb8fff);
+    for module in modules::module_tags(boot_info) {
+        if module.end_address() > module.start_address() {
+            boot_allocator.reserve(module.start_address(), module.end_address() - 1);
+        }
+    }
 
-    let mut active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
+    use self::paging::{Page, VirtualAllocator, RegionKind};
+    use hole_list_allocator::{self, HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE};
 
-    use self::paging::Page;
-    use hole_list_allocator::{HEAP_START, HEAP_SIZE};
+    // the heap allocator uses a fixed address, so we reserve its region before anything else
+    let mut virtual_allocator = VirtualAllocator::new();
+    let heap_region = virtual_allocator.reserve(Page::containing_address(HEAP_START),
+                                                HEAP_MAX_SIZE / PAGE_SIZE,
+                                                RegionKind::Heap)
+        .expect("heap region is not available");
+
+    let mut active_table =
+        paging::remap_the_kernel(&mut boot_allocator, &mut virtual_allocator, boot_info);
 
+    // we need the initial heap before the page fault handler is set up, so we map it eagerly.
+    // The rest of the heap region is mapped on demand.
     let heap_start_page = Page::containing_address(HEAP_START);
     let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
 
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
+        active_table.map(page, paging::WRITABLE, &mut boot_allocator).expect("out of memory");
     }
 
-    let stack_allocator = {
-        let stack_alloc_start = heap_end_page + 1;
-        let stack_alloc_end = stack_alloc_start + 100;
-        let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
-        stack_allocator::StackAllocator::new(stack_alloc_range)
+    // switch to an allocator that supports deallocation. Its bitmap is mapped to frames of the
+    // boot allocator, so the boot allocator must not be used afterwards.
+    let mut frame_allocator = BitmapFrameAllocator::new(memory_map_tag.memory_areas(),
+                                                        boot_allocator,
+                                                        &mut active_table,
+                                                        &mut virtual_allocator);
+
+    // the heap is backed from its own frame reserve, see `grow_heap`
+    heap::init(heap_region, &mut active_table, &mut frame_allocator);
+
+    let modules = modules::map_modules(boot_info,
+                                       &mut active_table,
+                                       &mut virtual_allocator,
+                                       &mut frame_allocator);
+
+    let kernel_frames = Frame::containing_address(kernel_end).number -
+                        Frame::containing_address(kernel_start).number + 1;
+
+    // a page for copying frames in copy-on-write faults
+    let copy_page = virtual_allocator.allocate(1, RegionKind::Temporary)
+        .expect("no virtual memory for copy page")
+        .start_page();
+
+    // write-combining mappings of device memory need the PAT
+    mmio::init_pat();
+
+    let memory_controller = MEMORY_CONTROLLER.call_once(|| {
+        Mutex::new(MemoryController {
+            active_table: active_table,
+            frame_allocator: frame_allocator,
+            buddy_allocator: BuddyFrameAllocator::new(),
+            stack_allocator: stack_allocator::StackAllocator::new(),
+            virtual_allocator: virtual_allocator,
+            kernel_frames: kernel_frames,
+            memory_regions: stats::memory_regions(memory_map_tag),
+            modules: modules,
+            shared_frames: SharedFrames::new(),
+            copy_page: copy_page,
+        })
+    });
+    hole_list_allocator::set_grow_handler(grow_heap);
+    memory_controller
+}
+
+/// Allows the heap to grow into the given range. The new pages are backed on demand by the page
+/// fault handler, so we only set aside frames for them. The heap allocator calls this function
+/// when it is full, so it must not allocate heap memory itself.
+///
+/// The memory controller might be locked by the code that tries to allocate, so the frames come
+/// from the heap reserve, which has its own lock. We only use the memory controller to top up the
+/// reserve if it happens to be available.
+fn grow_heap(start: usize, size: usize) -> bool {
+    if let Some(mut memory_controller) = MEMORY_CONTROLLER.try().and_then(|m| m.try_lock()) {
+        heap::refill(&mut memory_controller.frame_allocator);
+    }
+    heap::grow(start, size)
+}
+
+/// Backs the page that contains the faulting address with a zeroed frame if it lies in a lazily
+/// backed region (the heap). Returns `false` if the fault can't be resolved this way.
+///
+/// Called by the page fault handler for faults on non-present pages. It works while the memory
+/// controller is locked.
+pub fn handle_page_fault(address: VirtualAddress) -> bool {
+    heap::map_lazy_page(address)
+}
+
+/// Resolves a write fault on a copy-on-write page by giving the page its own copy of the shared
+/// frame. Returns `false` if the page isn't copy-on-write.
+///
+/// Called by the page fault handler for write faults on present pages.
+pub fn handle_copy_on_write_fault(address: VirtualAddress) -> bool {
+    use self::paging::Page;
+
+    let mut memory_controller = match MEMORY_CONTROLLER.try().and_then(|m| m.try_lock()) {
+        Some(memory_controller) => memory_controller,
+        None => return false,
+    };
+    memory_controller.copy_on_write(Page::containing_address(address))
+}
+
+/// Prints the heap statistics and all live allocations with their callers. It doesn't allocate,
+/// so it can be used in the panic handler.
+#[cfg(feature = "heap_stats")]
+pub fn print_heap_report() {
+    use hole_list_allocator::stats;
+
+    let heap_stats = match stats::heap_stats() {
+        Some(heap_stats) => heap_stats,
+        None => {
+            println!("heap stats are locked");
+            return;
+        }
     };
+    println!("heap: {} bytes live, {} bytes peak, {} allocations, {} deallocations",
+             heap_stats.live_bytes,
+             heap_stats.peak_bytes,
+             heap_stats.allocations,
+             heap_stats.deallocations);
+    for (i, count) in heap_stats.buckets.iter().enumerate() {
+        if i + 1 < stats::SIZE_BUCKETS {
+            print!("<={}: {}  ", 16 << i, count);
+        } else {
+            println!(">{}: {}", 16 << (i - 1), count);
+        }
+    }
 
-    MemoryController {
-        active_table: active_table,
-        frame_allocator: frame_allocator,
-        stack_allocator: stack_allocator,
+    stats::for_each_allocation(|allocation| {
+        print!("  {:#x} ({} bytes) from", allocation.address, allocation.size);
+        for &caller in allocation.callers.iter().filter(|&&caller| caller != 0) {
+            print!(" {:#x}", caller);
+        }
+        println!("");
+    });
+    if heap_stats.untracked > 0 {
+        println!("  ... and {} untracked allocations", heap_stats.untracked);
     }
 }
 
+#[cfg(not(feature = "heap_stats"))]
+pub fn print_heap_report() {}
+
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: BitmapFrameAllocator,
+    buddy_allocator: BuddyFrameAllocator,
     stack_allocator: stack_allocator::StackAllocator,
+    virtual_allocator: paging::VirtualAllocator,
+    kernel_frames: usize,
+    memory_regions: Vec<MemoryRegion>,
+    modules: Vec<Module>,
+    shared_frames: SharedFrames,
+    copy_page: paging::Page,
 }
 
 impl MemoryController {
     pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
         let &mut MemoryController { ref mut active_table,
                                     ref mut frame_allocator,
-                                    ref mut stack_allocator } = self;
-        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+                                    ref mut stack_allocator,
+                                    ref mut virtual_allocator,
+                                    ref mut shared_frames,
+                                    .. } = self;
+        let mut frame_allocator = SharingAllocator {
+            allocator: frame_allocator,
+            shared_frames: shared_frames,
+        };
+        stack_allocator.alloc_stack(active_table,
+                                    &mut frame_allocator,
+                                    virtual_allocator,
+                                    size_in_pages)
+    }
+
+    /// Maps `target` to the frame of the 4KiB page `page` and marks both pages copy-on-write. The
+    /// first write to either page gives it a private copy of the frame.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn share_copy_on_write(&mut self,
+                               page: paging::Page,
+                               target: paging::Page)
+                               -> Result<(), paging::MapError> {
+        let frame = try!(self.active_table.translate_page(page).ok_or(paging::MapError::NotMapped));
+        let was_shared = self.shared_frames.mappings(&frame) > 1;
+        if !self.shared_frames.share(&frame) {
+            return Err(paging::MapError::TooManySharedFrames);
+        }
+        let flags = match self.active_table.mark_copy_on_write(page) {
+            Ok(flags) => flags,
+            Err(error) => {
+                self.shared_frames.release(&frame);
+                return Err(error);
+            }
+        };
+
+        let result = self.active_table
+            .map_to(target, frame.clone(), flags, &mut self.frame_allocator);
+        if result.is_err() {
+            // `page` is the only mapping of the frame again, so it gets its old flags back
+            self.shared_frames.release(&frame);
+            if !was_shared {
+                self.active_table.resolve_copy_on_write(page, frame).unwrap();
+            }
+        }
+        result
+    }
+
+    /// Copies the shared frame of the given copy-on-write page and maps the page writable to the
+    /// copy. If the frame isn't shared anymore, the page is just made writable.
+    fn copy_on_write(&mut self, page: paging::Page) -> bool {
+        use core::ptr;
+
+        if !self.active_table.is_copy_on_write(page) {
+            return false;
+        }
+        let frame = self.active_table.translate_page(page).unwrap();
+
+        if self.shared_frames.mappings(&frame) == 1 {
+            // we are the last mapping of the frame
+            self.active_table.resolve_copy_on_write(page, frame).unwrap();
+            return true;
+        }
+
+        let copy = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        if self.active_table
+            .map_to(self.copy_page,
+                    copy.clone(),
+                    paging::WRITABLE,
+                    &mut self.frame_allocator)
+            .is_err() {
+            self.frame_allocator.deallocate_frame(copy);
+            return false;
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(page.start_address() as *const u8,
+                                     self.copy_page.start_address() as *mut u8,
+                                     PAGE_SIZE)
+        };
+        self.active_table.unmap_temporary(self.copy_page, &mut self.frame_allocator).unwrap();
+
+        // if the other page is the last mapping now, it doesn't need to copy anymore
+        self.shared_frames.release(&frame);
+        self.active_table.resolve_copy_on_write(page, copy).unwrap();
+        true
+    }
+
+    /// Unmaps the boot stack and frees its frames. The pages stay unmapped, so that stray
+    /// accesses cause a page fault. Must only be called after switching to another stack.
+    pub fn free_boot_stack(&mut self, bottom: VirtualAddress, top: VirtualAddress) {
+        use self::paging::Page;
+
+        let mut frame_allocator = SharingAllocator {
+            allocator: &mut self.frame_allocator,
+            shared_frames: &mut self.shared_frames,
+        };
+        let start_page = Page::containing_address(bottom);
+        let end_page = Page::containing_address(top - 1);
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = self.active_table
+                .unmap(page, &mut frame_allocator)
+                .expect("boot stack is not mapped");
+            frame_allocator.deallocate_frame(frame);
+            // the boot stack is part of the kernel's .bss section
+            self.kernel_frames -= 1;
+        }
+    }
+
+    /// Frees the given stack, so that its memory can be reused for new stacks.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn free_stack(&mut self, stack: Stack) {
+        let &mut MemoryController { ref mut active_table,
+                                    ref mut frame_allocator,
+                                    ref mut stack_allocator,
+                                    ref mut virtual_allocator,
+                                    ref mut shared_frames,
+                                    .. } = self;
+        let mut frame_allocator = SharingAllocator {
+            allocator: frame_allocator,
+            shared_frames: shared_frames,
+        };
+        stack_allocator.free_stack(stack, active_table, &mut frame_allocator, virtual_allocator)
+    }
+
+    /// Replaces the flags of all pages in `[start, start + size)`, similar to `mprotect`. For
+    /// example, a read-only page catches stray writes like a guard page.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn protect(&mut self,
+                   start: VirtualAddress,
+                   size: usize,
+                   flags: paging::EntryFlags)
+                   -> Result<(), paging::MapError> {
+        use self::paging::Page;
+
+        if size == 0 {
+            return Ok(());
+        }
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(start + size - 1);
+        self.active_table.update_flags_range(start_page, end_page, flags, &mut self.frame_allocator)
+    }
+
+    /// Maps the `size` bytes of device memory at `physical_address` with the given cache mode.
+    /// Returns `None` if there is not enough virtual memory or the range overlaps RAM.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn map_mmio(&mut self,
+                    physical_address: PhysicalAddress,
+                    size: usize,
+                    cache_mode: CacheMode)
+                    -> Option<Mmio> {
+        mmio::map_mmio(&mut self.active_table,
+                       &mut self.frame_allocator,
+                       &mut self.virtual_allocator,
+                       &self.memory_regions,
+                       physical_address,
+                       size,
+                       cache_mode)
+    }
+
+    /// Unmaps device memory that was mapped through `map_mmio`.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn unmap_mmio(&mut self, mmio: Mmio) {
+        mmio::unmap_mmio(mmio,
+                         &mut self.active_table,
+                         &mut self.frame_allocator,
+                         &mut self.virtual_allocator)
+    }
+
+    /// Allocates `2^order` physically contiguous frames, aligned to their size. Pass `LIMIT_4GIB`
+    /// as `limit` for memory that needs to be reachable by 32-bit devices.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn allocate_frames(&mut self,
+                           order: usize,
+                           limit: Option<PhysicalAddress>)
+                           -> Option<Frame> {
+        self.buddy_allocator.allocate_frames(order, limit, &mut self.frame_allocator)
+    }
+
+    /// Returns the current physical memory usage.
+    pub fn stats(&self) -> MemoryStats {
+        use self::paging::Page;
+        use hole_list_allocator::{self, HEAP_START};
+
+        let heap_start_page = Page::containing_address(HEAP_START);
+        let heap_end_page =
+            Page::containing_address(HEAP_START + hole_list_allocator::heap_size() - 1);
+        let heap_frames = Page::range_inclusive(heap_start_page, heap_end_page)
+            .filter(|&page| self.active_table.translate_page(page).is_some())
+            .count();
+
+        MemoryStats {
+            usable_frames: self.frame_allocator.usable_frames(),
+            free_frames: self.frame_allocator.free_frames(),
+            kernel_frames: self.kernel_frames,
+            page_table_frames: self.active_table.page_table_count(),
+            heap_frames: heap_frames,
+            stack_frames: self.stack_allocator.mapped_pages(),
+        }
+    }
+
+    /// Prints all mappings of the active page table.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn dump_page_tables(&self) {
+        self.active_table.dump();
+    }
+
+    /// Returns all areas of the multiboot memory map, including the unusable ones.
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        &self.memory_regions
+    }
+
+    /// Returns the boot modules that were loaded by the bootloader.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Frees a block that was allocated through `allocate_frames` with the same `order`.
+    #[cfg_attr(not(feature = "boot_tests"), allow(dead_code))]
+    pub fn deallocate_frames(&mut self, frame: Frame, order: usize) {
+        self.buddy_allocator.deallocate_frames(frame, order, &mut self.frame_allocator)
     }
 }
 
@@ -139,3 +525,15 @@ pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
 }
+
+/// Executes the `cpuid` instruction and returns `eax`, `ebx`, `ecx`, and `edx`.
+fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :: "volatile")
+    };
+    (eax, ebx, ecx, edx)
+}
@@ -0,0 +1,152 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::paging::{self, ActivePageTable, VirtualAddress, VirtualAllocator, RegionKind};
+use multiboot2::BootInformation;
+use collections::{String, Vec};
+use core::{slice, str};
+
+/// A boot module that was loaded by the bootloader (through a `module2` command in GRUB).
+pub struct Module {
+    cmdline: String,
+    start_address: VirtualAddress,
+    size: usize,
+}
+
+impl Module {
+    /// Returns the command line of the module, i.e. the arguments after the file name of the
+    /// `module2` command.
+    pub fn cmdline(&self) -> &str {
+        &self.cmdline
+    }
+
+    /// Returns the content of the module. It is mapped read-only.
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.start_address as *const u8, self.size) }
+    }
+}
+
+/// The multiboot2 tag that describes a boot module. It is followed by the null-terminated
+/// command line of the module.
+#[repr(C)]
+pub struct ModuleTag {
+    typ: u32,
+    size: u32,
+    mod_start: u32,
+    mod_end: u32,
+}
+
+impl ModuleTag {
+    /// Returns the physical start address of the module.
+    pub fn start_address(&self) -> usize {
+        self.mod_start as usize
+    }
+
+    /// Returns the physical end address of the module (exclusive).
+    pub fn end_address(&self) -> usize {
+        self.mod_end as usize
+    }
+
+    fn cmdline(&self) -> &str {
+        use core::mem::size_of;
+
+        let address = self as *const _ as usize + size_of::<ModuleTag>();
+        // the size includes the null byte
+        let length = (self.size as usize).saturating_sub(size_of::<ModuleTag>() + 1);
+        let bytes = unsafe { slice::from_raw_parts(address as *const u8, length) };
+        str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+/// Returns an iterator over the module tags of the multiboot information structure. The
+/// multiboot2 crate doesn't support module tags yet, so we walk the tag list ourselves.
+pub fn module_tags(boot_info: &BootInformation) -> ModuleTagIter {
+    ModuleTagIter {
+        // skip the `total_size` and `reserved` fields
+        current: boot_info.start_address() + 8,
+        end: boot_info.end_address(),
+    }
+}
+
+pub struct ModuleTagIter {
+    current: usize,
+    end: usize,
+}
+
+impl Iterator for ModuleTagIter {
+    type Item = &'static ModuleTag;
+
+    fn next(&mut self) -> Option<&'static ModuleTag> {
+        const END_TAG_TYPE: u32 = 0;
+        const MODULE_TAG_TYPE: u32 = 3;
+
+        while self.current + 8 <= self.end {
+            let tag = unsafe { &*(self.current as *const ModuleTag) };
+            if tag.typ == END_TAG_TYPE || tag.size < 8 {
+                break;
+            }
+            // tags are 8 byte aligned
+            self.current = (self.current + tag.size as usize + 7) & !7;
+            if tag.typ == MODULE_TAG_TYPE {
+                return Some(tag);
+            }
+        }
+        None
+    }
+}
+
+/// Maps all boot modules read-only behind each other into a `Modules` region. The frames of the
+/// modules must be reserved in the frame allocator.
+pub fn map_modules<A>(boot_info: &BootInformation,
+                      active_table: &mut ActivePageTable,
+                      virtual_allocator: &mut VirtualAllocator,
+                      allocator: &mut A)
+                      -> Vec<Module>
+    where A: FrameAllocator
+{
+    let frame_range = |tag: &ModuleTag| {
+        let start_frame = Frame::containing_address(tag.start_address());
+        let end_frame = Frame::containing_address(tag.end_address() - 1);
+        Frame::range_inclusive(start_frame, end_frame)
+    };
+
+    let page_count: usize = module_tags(boot_info)
+        .filter(|tag| tag.end_address() > tag.start_address())
+        .map(|tag| frame_range(tag).count())
+        .sum();
+    let mut modules = Vec::new();
+    if page_count == 0 {
+        return modules;
+    }
+
+    let region = virtual_allocator.allocate(page_count, RegionKind::Modules)
+        .expect("no virtual memory for boot modules");
+    let mut next_page = region.start_page();
+
+    for tag in module_tags(boot_info) {
+        let size = tag.end_address() - tag.start_address();
+        let start_address = next_page.start_address() + tag.start_address() % PAGE_SIZE;
+
+        if size > 0 {
+            for frame in frame_range(tag) {
+                active_table.map_to(next_page, frame, paging::NO_EXECUTE, allocator)
+                    .expect("failed to map boot module");
+                next_page = next_page + 1;
+            }
+        }
+
+        modules.push(Module {
+            cmdline: String::from(tag.cmdline()),
+            start_address: start_address,
+            size: size,
+        });
+    }
+    modules
+}
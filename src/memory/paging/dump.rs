@@ -0,0 +1,173 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{VirtualAddress, PhysicalAddress, Mapper, ENTRY_COUNT, RECURSIVE_INDEX};
+use super::entry::*;
+use memory::PAGE_SIZE;
+use core::fmt;
+
+/// A run of contiguous pages that map contiguous frames with identical flags and page size.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub start: VirtualAddress,
+    /// The last address of the run (inclusive), so that a run at the end of the address space
+    /// doesn't overflow.
+    pub end: VirtualAddress,
+    pub physical_start: PhysicalAddress,
+    /// The size of the pages of the run: 4KiB, 2MiB, or 1GiB.
+    pub page_size: usize,
+    /// The flags in the format of P1 entries, also for huge pages. So `PAT` is bit 7 and
+    /// `HUGE_PAGE` is never set.
+    pub flags: EntryFlags,
+}
+
+impl Mapping {
+    // returns whether the given page directly continues the run
+    fn continues(&self, other: &Mapping) -> bool {
+        self.end.checked_add(1) == Some(other.start) &&
+        other.physical_start == self.physical_start + (self.end - self.start + 1) &&
+        other.page_size == self.page_size && other.flags == self.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "{:#x}-{:#x} -> phys {:#x} R{}{}",
+                    self.start,
+                    // exclusive like the memory regions, wraps to 0 at the end of the address space
+                    self.end.wrapping_add(1),
+                    self.physical_start,
+                    if self.flags.contains(WRITABLE) { 'W' } else { '-' },
+                    if self.flags.contains(NO_EXECUTE) { '-' } else { 'X' }));
+        if self.flags.contains(USER_ACCESSIBLE) {
+            try!(write!(f, " user"));
+        }
+        if self.flags.contains(GLOBAL) {
+            try!(write!(f, " global"));
+        }
+        if self.flags.contains(NO_CACHE) {
+            try!(write!(f, " uncached"));
+        }
+        if self.flags.contains(COPY_ON_WRITE) {
+            try!(write!(f, " cow"));
+        }
+        match self.page_size {
+            0x4000_0000 => write!(f, " (1GiB pages)"),
+            0x20_0000 => write!(f, " (2MiB pages)"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Mapper {
+    /// Calls `f` for every run of mapped pages in the page tables, in ascending address order.
+    /// Huge pages are reported with their size. The recursive entry is skipped.
+    ///
+    /// To inspect an `InactivePageTable`, call this on the `Mapper` inside of
+    /// `ActivePageTable::with`.
+    pub fn for_each_mapping<F>(&self, mut f: F)
+        where F: FnMut(Mapping)
+    {
+        // the accessed and dirty bits differ between otherwise identical pages
+        let relevant_flags = |flags: EntryFlags| flags - ACCESSED - DIRTY;
+
+        let mut run: Option<Mapping> = None;
+        {
+            let mut add = |mapping: Mapping| {
+                if let Some(ref mut current) = run {
+                    if current.continues(&mapping) {
+                        current.end = mapping.end;
+                        return;
+                    }
+                    f(*current);
+                }
+                run = Some(mapping);
+            };
+
+            for p4_index in (0..ENTRY_COUNT).filter(|&i| i != RECURSIVE_INDEX) {
+                let p3 = match self.p4().next_table(p4_index) {
+                    Some(p3) => p3,
+                    None => continue,
+                };
+                for p3_index in 0..ENTRY_COUNT {
+                    let entry = &p3[p3_index];
+                    let address = virtual_address(p4_index, p3_index, 0, 0);
+                    if let Some(frame) = entry.huge_page_frame() {
+                        add(mapping(address, frame.start_address(), 0x4000_0000,
+                                    relevant_flags(entry.huge_page_flags().huge_to_p1())));
+                        continue;
+                    }
+                    let p2 = match p3.next_table(p3_index) {
+                        Some(p2) => p2,
+                        None => continue,
+                    };
+                    for p2_index in 0..ENTRY_COUNT {
+                        let entry = &p2[p2_index];
+                        let address = virtual_address(p4_index, p3_index, p2_index, 0);
+                        if let Some(frame) = entry.huge_page_frame() {
+                            add(mapping(address, frame.start_address(), 0x20_0000,
+                                        relevant_flags(entry.huge_page_flags().huge_to_p1())));
+                            continue;
+                        }
+                        let p1 = match p2.next_table(p2_index) {
+                            Some(p1) => p1,
+                            None => continue,
+                        };
+                        for p1_index in 0..ENTRY_COUNT {
+                            let entry = &p1[p1_index];
+                            if let Some(frame) = entry.pointed_frame() {
+                                let address =
+                                    virtual_address(p4_index, p3_index, p2_index, p1_index);
+                                add(mapping(address, frame.start_address(), PAGE_SIZE,
+                                            relevant_flags(entry.flags())));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(current) = run {
+            f(current);
+        }
+    }
+
+    /// Prints all mappings of the page tables, see `for_each_mapping`.
+    pub fn dump(&self) {
+        self.for_each_mapping(|mapping| println!("{}", mapping));
+    }
+}
+
+fn mapping(start: VirtualAddress,
+           physical_start: PhysicalAddress,
+           page_size: usize,
+           flags: EntryFlags)
+           -> Mapping {
+    Mapping {
+        start: start,
+        end: start + (page_size - 1),
+        physical_start: physical_start,
+        page_size: page_size,
+        flags: flags,
+    }
+}
+
+/// Returns the canonical virtual address of the given table indexes.
+fn virtual_address(p4_index: usize,
+                   p3_index: usize,
+                   p2_index: usize,
+                   p1_index: usize)
+                   -> VirtualAddress {
+    let address = (p4_index << 39) | (p3_index << 30) | (p2_index << 21) | (p1_index << 12);
+    if address & (1 << 47) != 0 {
+        address | 0xffff_0000_0000_0000
+    } else {
+        address
+    }
+}
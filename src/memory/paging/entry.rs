@@ -21,10 +21,19 @@ impl Entry {
         self.0 = 0;
     }
 
+    /// Returns the flags of the entry. For huge page entries, use `huge_page_flags` instead.
     pub fn flags(&self) -> EntryFlags {
+        // bit 12 is an address bit, except in huge page entries
+        EntryFlags::from_bits_truncate(self.0) - HUGE_PAGE_PAT
+    }
+
+    /// Returns the flags of a P2 or P3 entry that maps a huge page, including `HUGE_PAGE_PAT`.
+    pub fn huge_page_flags(&self) -> EntryFlags {
         EntryFlags::from_bits_truncate(self.0)
     }
 
+    /// Returns the frame of a P1 entry or the next table of a higher level entry. For huge page
+    /// entries, use `huge_page_frame` instead.
     pub fn pointed_frame(&self) -> Option<Frame> {
         if self.flags().contains(PRESENT) {
             Some(Frame::containing_address(self.0 as usize & 0x000fffff_fffff000))
@@ -33,6 +42,16 @@ impl Entry {
         }
     }
 
+    /// Returns the first frame of the huge page that this P2 or P3 entry maps. Bit 12 is the PAT
+    /// bit in these entries, so it's not part of the address.
+    pub fn huge_page_frame(&self) -> Option<Frame> {
+        if self.flags().contains(PRESENT | HUGE_PAGE) {
+            Some(Frame::containing_address(self.0 as usize & 0x000fffff_ffffe000))
+        } else {
+            None
+        }
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
         self.0 = (frame.start_address() as u64) | flags.bits();
@@ -50,11 +69,41 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        // selects the upper half of the PAT in P1 entries. It is the same bit as `HUGE_PAGE`, so
+        // the `Debug` output of a write-combining P1 entry contains `HUGE_PAGE` too. Code that
+        // walks the tables must only treat bit 7 as `HUGE_PAGE` in P2 and P3 entries.
+        const PAT =             1 << 7,
+        // software defined: the page shares its frame and is copied on the first write
+        const COPY_ON_WRITE =   1 << 9,
+        // software defined: the copy-on-write page becomes writable when it gets its own frame
+        const WRITABLE_AFTER_COPY = 1 << 10,
+        // selects the upper half of the PAT in P2 and P3 entries that map huge pages
+        const HUGE_PAGE_PAT =   1 << 12,
         const NO_EXECUTE =      1 << 63,
     }
 }
 
 impl EntryFlags {
+    /// Converts the flags of a huge page entry to the flags of the P1 entries that map the same
+    /// memory. The PAT bit moves from bit 12 to bit 7.
+    pub fn huge_to_p1(self) -> EntryFlags {
+        let mut flags = self - HUGE_PAGE - HUGE_PAGE_PAT;
+        if self.contains(HUGE_PAGE_PAT) {
+            flags.insert(PAT);
+        }
+        flags
+    }
+
+    /// Converts the flags of a P1 entry to the flags of a huge page entry that maps the same
+    /// memory. The PAT bit moves from bit 7 to bit 12.
+    pub fn p1_to_huge(self) -> EntryFlags {
+        let mut flags = (self - PAT) | HUGE_PAGE;
+        if self.contains(PAT) {
+            flags.insert(HUGE_PAGE_PAT);
+        }
+        flags
+    }
+
     pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
         use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
 
@@ -7,19 +7,64 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT, RECURSIVE_INDEX};
 use super::entry::*;
-use super::table::{self, Table, Level4};
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use super::table::{Table, Level4};
+#[cfg(feature = "physical_map")]
+use super::physical_map_address;
+use super::table::HierarchicalLevel;
+use memory::{PAGE_SIZE, KERNEL_OFFSET, Frame, FrameAllocator, cpuid};
 use core::ptr::Unique;
 
+/// The errors of the operations that create or change mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page is not mapped, so its mapping can't be changed.
+    NotMapped,
+    /// There is no frame for a new page table.
+    FrameAllocationFailed,
+    /// The page overlaps a mapping of a different page size.
+    HugePageConflict,
+    /// Too many frames are shared copy-on-write already.
+    TooManySharedFrames,
+    /// The CPU doesn't support 1GiB pages.
+    HugePageUnsupported,
+}
+
+/// The errors of the operations that remove mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmapError {
+    /// The page is not mapped.
+    NotMapped,
+    /// Splitting a huge page needs a frame for the new page table, but there is none.
+    FrameAllocationFailed,
+    /// The page is mapped with a different page size.
+    HugePageConflict,
+}
+
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
 }
 
 impl Mapper {
+    #[cfg(not(feature = "physical_map"))]
+    pub unsafe fn new() -> Mapper {
+        Mapper { p4: Unique::new(super::table::P4) }
+    }
+
+    #[cfg(feature = "physical_map")]
     pub unsafe fn new() -> Mapper {
-        Mapper { p4: Unique::new(table::P4) }
+        use x86::shared::control_regs;
+
+        Mapper::for_table(Frame::containing_address(control_regs::cr3() as usize))
+    }
+
+    /// Creates a mapper for the P4 table in the given frame, which doesn't need to be active.
+    #[cfg(feature = "physical_map")]
+    pub unsafe fn for_table(p4_frame: Frame) -> Mapper {
+        Mapper { p4: Unique::new(physical_map_address(&p4_frame) as *mut _) }
     }
 
     pub fn p4(&self) -> &Table<Level4> {
@@ -43,25 +88,21 @@ impl Mapper {
             p3.and_then(|p3| {
                 let p3_entry = &p3[page.p3_index()];
                 // 1GiB page?
-                if let Some(start_frame) = p3_entry.pointed_frame() {
-                    if p3_entry.flags().contains(HUGE_PAGE) {
-                        // address must be 1GiB aligned
-                        assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
-                        return Some(Frame {
-                            number: start_frame.number + page.p2_index() * ENTRY_COUNT +
-                                    page.p1_index(),
-                        });
-                    }
+                if let Some(start_frame) = p3_entry.huge_page_frame() {
+                    // address must be 1GiB aligned
+                    assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
+                    return Some(Frame {
+                        number: start_frame.number + page.p2_index() * ENTRY_COUNT +
+                                page.p1_index(),
+                    });
                 }
                 if let Some(p2) = p3.next_table(page.p3_index()) {
                     let p2_entry = &p2[page.p2_index()];
                     // 2MiB page?
-                    if let Some(start_frame) = p2_entry.pointed_frame() {
-                        if p2_entry.flags().contains(HUGE_PAGE) {
-                            // address must be 2MiB aligned
-                            assert!(start_frame.number % ENTRY_COUNT == 0);
-                            return Some(Frame { number: start_frame.number + page.p1_index() });
-                        }
+                    if let Some(start_frame) = p2_entry.huge_page_frame() {
+                        // address must be 2MiB aligned
+                        assert!(start_frame.number % ENTRY_COUNT == 0);
+                        return Some(Frame { number: start_frame.number + page.p1_index() });
                     }
                 }
                 None
@@ -74,45 +115,559 @@ impl Mapper {
             .or_else(huge_page)
     }
 
-    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    /// Returns the number of page tables that are reachable from the P4 table, including the P4
+    /// table itself.
+    pub fn page_table_count(&self) -> usize {
+        let mut count = 1;
+        // skip the recursive entry, it points to the P4 table again
+        for p3 in (0..ENTRY_COUNT)
+            .filter(|&i| i != RECURSIVE_INDEX)
+            .filter_map(|i| self.p4().next_table(i)) {
+            count += 1;
+            for p2 in (0..ENTRY_COUNT).filter_map(|i| p3.next_table(i)) {
+                count += 1;
+                count += (0..ENTRY_COUNT).filter(|&i| p2.next_table(i).is_some()).count();
+            }
+        }
+        count
+    }
+
+    /// Maps the given page to the given frame. Fails if the page is already mapped, if it is part
+    /// of a huge page, or if there are no frames for new page tables.
+    pub fn map_to<A>(&mut self,
+                     page: Page,
+                     frame: Frame,
+                     flags: EntryFlags,
+                     allocator: &mut A)
+                     -> Result<(), MapError>
         where A: FrameAllocator
     {
-        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+        let result = self.set_p1_entry(page, frame, flags, allocator);
+        if result.is_err() {
+            // free the tables that were created before the error
+            self.free_empty_tables(page, allocator);
+        }
+        result
+    }
 
-        assert!(p1[page.p1_index()].is_unused());
-        p1[page.p1_index()].set(frame, flags | PRESENT);
+    /// Creates the page tables that are needed to map the given page, without mapping it. Later
+    /// mappings of pages in the same 2MiB region don't need frames for page tables then.
+    pub fn create_tables<A>(&mut self, page: Page, allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let p3 = try!(create_next_table(self.p4_mut(), page.p4_index(), allocator));
+        let p2 = try!(create_next_table(p3, page.p3_index(), allocator));
+        try!(create_next_table(p2, page.p2_index(), allocator));
+        Ok(())
     }
 
-    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+    /// Maps the given page to a newly allocated frame.
+    pub fn map<A>(&mut self,
+                  page: Page,
+                  flags: EntryFlags,
+                  allocator: &mut A)
+                  -> Result<(), MapError>
         where A: FrameAllocator
     {
-        let frame = allocator.allocate_frame().expect("out of memory");
-        self.map_to(page, frame, flags, allocator)
+        let frame = try!(allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed));
+        let result = self.map_to(page, frame.clone(), flags, allocator);
+        if result.is_err() {
+            allocator.deallocate_frame(frame);
+        }
+        result
     }
 
-    pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    /// Maps the given frame to its address in the higher half, `KERNEL_OFFSET` above its physical
+    /// address.
+    pub fn higher_half_map<A>(&mut self,
+                              frame: Frame,
+                              flags: EntryFlags,
+                              allocator: &mut A)
+                              -> Result<(), MapError>
         where A: FrameAllocator
     {
-        let page = Page::containing_address(frame.start_address());
+        let page = Page::containing_address(frame.start_address() + KERNEL_OFFSET);
         self.map_to(page, frame, flags, allocator)
     }
 
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    /// Maps the 2MiB page that starts at `page` to the 2MiB frame that starts at `frame`. Both
+    /// need to be 2MiB aligned. The flags are the same as for 4KiB pages, i.e. `PAT` is bit 7.
+    /// Fails with `HugePageConflict` if a part of the page is mapped through 4KiB pages or a 1GiB
+    /// page.
+    pub fn map_to_2mib<A>(&mut self,
+                          page: Page,
+                          frame: Frame,
+                          flags: EntryFlags,
+                          allocator: &mut A)
+                          -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert!(page.number % ENTRY_COUNT == 0,
+                "page {:#x} is not 2MiB aligned",
+                page.start_address());
+        assert!(frame.number % ENTRY_COUNT == 0,
+                "frame {:#x} is not 2MiB aligned",
+                frame.start_address());
+
+        let result = {
+            let p3 = try!(create_next_table(self.p4_mut(), page.p4_index(), allocator));
+            create_next_table(p3, page.p3_index(), allocator).and_then(|p2| {
+                let entry = &mut p2[page.p2_index()];
+                if entry.flags().contains(PRESENT | HUGE_PAGE) {
+                    Err(MapError::AlreadyMapped)
+                } else if !entry.is_unused() {
+                    Err(MapError::HugePageConflict)
+                } else {
+                    entry.set(frame, flags.p1_to_huge() | PRESENT);
+                    Ok(())
+                }
+            })
+        };
+        if result.is_err() {
+            self.free_empty_tables(page, allocator);
+        }
+        result
+    }
+
+    /// Maps the 1GiB page that starts at `page` to the 1GiB frame that starts at `frame`. Both
+    /// need to be 1GiB aligned. The flags are the same as for 4KiB pages. Fails with
+    /// `HugePageUnsupported` if the CPU doesn't support 1GiB pages and with `HugePageConflict` if
+    /// a part of the page is mapped through smaller pages.
+    pub fn map_to_1gib<A>(&mut self,
+                          page: Page,
+                          frame: Frame,
+                          flags: EntryFlags,
+                          allocator: &mut A)
+                          -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert!(page.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "page {:#x} is not 1GiB aligned",
+                page.start_address());
+        assert!(frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "frame {:#x} is not 1GiB aligned",
+                frame.start_address());
+        try!(check_1gib_pages());
+
+        let result = create_next_table(self.p4_mut(), page.p4_index(), allocator).and_then(|p3| {
+            let entry = &mut p3[page.p3_index()];
+            if entry.flags().contains(PRESENT | HUGE_PAGE) {
+                Err(MapError::AlreadyMapped)
+            } else if !entry.is_unused() {
+                Err(MapError::HugePageConflict)
+            } else {
+                entry.set(frame, flags.p1_to_huge() | PRESENT);
+                Ok(())
+            }
+        });
+        if result.is_err() {
+            self.free_empty_tables(page, allocator);
+        }
+        result
+    }
+
+    /// Unmaps the given page and returns the frame it was mapped to. If the page is part of a
+    /// huge page, the huge page is split first, so that only the given page is unmapped. Page
+    /// tables that no longer contain any entries are freed.
+    ///
+    /// The frame of a copy-on-write page might still be mapped by other pages, so it must only be
+    /// freed through the memory controller's count of shared frames.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Result<Frame, UnmapError>
+        where A: FrameAllocator
+    {
+        self.unmap_and_flush(page, allocator, super::pcid::flush_page)
+    }
+
+    /// Unmaps a page that was mapped while the active address space stayed active, e.g. a
+    /// `TemporaryPage`. Unlike `unmap`, it doesn't force other address spaces to flush their TLB
+    /// on the next switch, since they never saw the mapping.
+    pub fn unmap_temporary<A>(&mut self,
+                              page: Page,
+                              allocator: &mut A)
+                              -> Result<Frame, UnmapError>
+        where A: FrameAllocator
+    {
+        self.unmap_and_flush(page, allocator, super::pcid::flush_local_page)
+    }
+
+    fn unmap_and_flush<A>(&mut self,
+                          page: Page,
+                          allocator: &mut A,
+                          flush: fn(VirtualAddress))
+                          -> Result<Frame, UnmapError>
+        where A: FrameAllocator
+    {
+        if self.translate_page(page).is_none() {
+            return Err(UnmapError::NotMapped);
+        }
+
+        let frame = {
+            // `next_table_create` splits huge pages, the tables exist otherwise
+            let p1 = try!(self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .unwrap()
+                .next_table_create(page.p3_index(), allocator)
+                .and_then(|p2| p2.next_table_create(page.p2_index(), allocator))
+                .ok_or(UnmapError::FrameAllocationFailed));
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            p1[page.p1_index()].set_unused();
+            frame
+        };
+        flush(page.start_address());
+        self.free_empty_tables(page, allocator);
+        Ok(frame)
+    }
+
+    /// Unmaps the 2MiB page that starts at `page` and returns its first frame. If the page is
+    /// part of a 1GiB page, the 1GiB page is split first. Fails with `HugePageConflict` if the
+    /// page is mapped through 4KiB pages.
+    pub fn unmap_2mib<A>(&mut self, page: Page, allocator: &mut A) -> Result<Frame, UnmapError>
+        where A: FrameAllocator
+    {
+        assert!(page.number % ENTRY_COUNT == 0,
+                "page {:#x} is not 2MiB aligned",
+                page.start_address());
+        if self.translate_page(page).is_none() {
+            return Err(UnmapError::NotMapped);
+        }
+
+        let frame = {
+            let p2 = try!(self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .unwrap()
+                .next_table_create(page.p3_index(), allocator)
+                .ok_or(UnmapError::FrameAllocationFailed));
+            let frame = match p2[page.p2_index()].huge_page_frame() {
+                Some(frame) => frame,
+                None => return Err(UnmapError::HugePageConflict),
+            };
+            p2[page.p2_index()].set_unused();
+            frame
+        };
+        super::pcid::flush_page(page.start_address());
+        self.free_empty_tables(page, allocator);
+        Ok(frame)
+    }
+
+    /// Unmaps the 1GiB page that starts at `page` and returns its first frame. Fails with
+    /// `HugePageConflict` if the page is mapped through smaller pages.
+    pub fn unmap_1gib<A>(&mut self, page: Page, allocator: &mut A) -> Result<Frame, UnmapError>
+        where A: FrameAllocator
+    {
+        assert!(page.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "page {:#x} is not 1GiB aligned",
+                page.start_address());
+
+        let frame = {
+            let p3 = try!(self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .ok_or(UnmapError::NotMapped));
+            let entry = &mut p3[page.p3_index()];
+            if entry.is_unused() {
+                return Err(UnmapError::NotMapped);
+            }
+            let frame = match entry.huge_page_frame() {
+                Some(frame) => frame,
+                None => return Err(UnmapError::HugePageConflict),
+            };
+            entry.set_unused();
+            frame
+        };
+        super::pcid::flush_page(page.start_address());
+        self.free_empty_tables(page, allocator);
+        Ok(frame)
+    }
+
+    /// Replaces the flags of the mapping of the given page. If the page is mapped through a huge
+    /// page, it must be the start of the huge page and the flags of the whole huge page change.
+    ///
+    /// The flags are the same as for 4KiB pages, also for huge pages. The `PRESENT` and
+    /// `HUGE_PAGE` bits are kept. The cache mode (`NO_CACHE`, `WRITE_THROUGH`, and `PAT`) is kept
+    /// too, unless `flags` contains one of its bits.
+    ///
+    /// Copy-on-write pages stay copy-on-write and read-only, so that the first write still copies
+    /// the frame. The copy is only writable if `flags` contains `WRITABLE`.
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+        {
+            let (entry, frames_per_page) = try!(self.entry_mut(page).ok_or(MapError::NotMapped));
+            if page.number % frames_per_page != 0 {
+                return Err(MapError::HugePageConflict);
+            }
+            let old_flags = if frames_per_page > 1 {
+                entry.huge_page_flags().huge_to_p1()
+            } else {
+                entry.flags()
+            };
+            let cache_flags = NO_CACHE | WRITE_THROUGH | PAT;
+            let mut new_flags = flags | PRESENT | (old_flags & (ACCESSED | DIRTY | COPY_ON_WRITE));
+            if !flags.intersects(cache_flags) {
+                new_flags.insert(old_flags & cache_flags);
+            }
+            if old_flags.contains(COPY_ON_WRITE) {
+                new_flags.remove(WRITABLE | WRITABLE_AFTER_COPY);
+                if flags.contains(WRITABLE) {
+                    new_flags.insert(WRITABLE_AFTER_COPY);
+                }
+            }
+            if frames_per_page > 1 {
+                let frame = entry.huge_page_frame().unwrap();
+                entry.set(frame, new_flags.p1_to_huge());
+            } else {
+                let frame = entry.pointed_frame().unwrap();
+                entry.set(frame, new_flags);
+            }
+        }
+        super::pcid::flush_page(page.start_address());
+        Ok(())
+    }
+
+    /// Replaces the flags of all pages from `start` to `end` (inclusive), see `update_flags`.
+    /// Huge pages that lie only partly in the range are split first.
+    ///
+    /// Fails with `NotMapped` at the first unmapped page. The pages before it keep their new
+    /// flags.
+    pub fn update_flags_range<A>(&mut self,
+                                 start: Page,
+                                 end: Page,
+                                 flags: EntryFlags,
+                                 allocator: &mut A)
+                                 -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let mut page = start;
+        while page <= end {
+            let frames_per_page = match self.entry_mut(page) {
+                Some((_, frames_per_page)) => frames_per_page,
+                None => return Err(MapError::NotMapped),
+            };
+            let covered = page.number % frames_per_page == 0 &&
+                          page.number + frames_per_page - 1 <= end.number;
+            if !covered {
+                // only a part of the huge page changes, so we split it and try again
+                try!(self.split_huge_page(page, frames_per_page, allocator));
+                continue;
+            }
+            try!(self.update_flags(page, flags));
+            page = page + frames_per_page;
+        }
+        Ok(())
+    }
+
+    /// Merges the 512 4KiB pages of the 2MiB region that starts at `page` into a single 2MiB
+    /// page. This only works if they map contiguous, 2MiB aligned frames with identical flags.
+    /// Returns whether the pages were merged.
+    pub fn collapse_2mib<A>(&mut self, page: Page, allocator: &mut A) -> bool
         where A: FrameAllocator
     {
-        assert!(self.translate(page.start_address()).is_some());
+        assert!(page.number % ENTRY_COUNT == 0,
+                "page {:#x} is not 2MiB aligned",
+                page.start_address());
 
-        let p1 = self.p4_mut()
+        self.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .map(|p2| p2.collapse_next_table(page.p2_index(), allocator))
+            .unwrap_or(false)
+    }
+
+    /// Merges the 512 2MiB pages of the 1GiB region that starts at `page` into a single 1GiB
+    /// page. Regions that are mapped through 4KiB pages need to be collapsed to 2MiB pages first.
+    /// Returns whether the pages were merged, which is never the case if the CPU doesn't support
+    /// 1GiB pages.
+    pub fn collapse_1gib<A>(&mut self, page: Page, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        assert!(page.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "page {:#x} is not 1GiB aligned",
+                page.start_address());
+        if check_1gib_pages().is_err() {
+            // the region stays mapped through 2MiB pages
+            return false;
+        }
+
+        self.p4_mut()
+            .next_table_mut(page.p4_index())
+            .map(|p3| p3.collapse_next_table(page.p3_index(), allocator))
+            .unwrap_or(false)
+    }
+
+    /// Marks the given 4KiB page as copy-on-write. The page becomes read-only, so that the first
+    /// write to it causes a page fault. Returns the new flags of the page.
+    ///
+    /// Only pages that were writable become writable again when they get their own frame.
+    pub fn mark_copy_on_write(&mut self, page: Page) -> Result<EntryFlags, MapError> {
+        let flags = {
+            let entry = try!(self.p1_entry_mut(page));
+            let frame = entry.pointed_frame().unwrap();
+            let mut flags = (entry.flags() - WRITABLE) | COPY_ON_WRITE;
+            if entry.flags().contains(WRITABLE) {
+                flags.insert(WRITABLE_AFTER_COPY);
+            }
+            entry.set(frame, flags);
+            flags
+        };
+        super::pcid::flush_page(page.start_address());
+        Ok(flags)
+    }
+
+    /// Returns whether the given page is mapped copy-on-write and becomes writable on a copy. A
+    /// write to a read-only copy-on-write page is a real protection fault.
+    pub fn is_copy_on_write(&self, page: Page) -> bool {
+        self.p4()
+            .next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map(|p1| {
+                p1[page.p1_index()].flags().contains(PRESENT | COPY_ON_WRITE | WRITABLE_AFTER_COPY)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Maps the copy-on-write page to the given frame (a copy of the shared frame or the shared
+    /// frame itself if it's no longer shared). The page becomes writable again if it was writable
+    /// before it was marked.
+    pub fn resolve_copy_on_write(&mut self, page: Page, frame: Frame) -> Result<(), MapError> {
+        {
+            let entry = try!(self.p1_entry_mut(page));
+            assert!(entry.flags().contains(COPY_ON_WRITE),
+                    "page {:#x} is not copy-on-write",
+                    page.start_address());
+            let mut flags = entry.flags() - COPY_ON_WRITE - WRITABLE_AFTER_COPY;
+            if entry.flags().contains(WRITABLE_AFTER_COPY) {
+                flags.insert(WRITABLE);
+            }
+            entry.set(frame, flags);
+        }
+        super::pcid::flush_page(page.start_address());
+        Ok(())
+    }
+
+    /// Returns the P1 entry of the given page. Fails if the page is not mapped or part of a huge
+    /// page.
+    fn p1_entry_mut(&mut self, page: Page) -> Result<&mut Entry, MapError> {
+        if self.translate_page(page).is_none() {
+            return Err(MapError::NotMapped);
+        }
+        self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
-        unsafe { ::x86::shared::tlb::flush(page.start_address()) };
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
+            .map(|p1| &mut p1[page.p1_index()])
+            .ok_or(MapError::HugePageConflict)
+    }
+
+    /// Returns the present entry that maps the given page and the number of frames it maps, or
+    /// `None` if the page is not mapped.
+    fn entry_mut(&mut self, page: Page) -> Option<(&mut Entry, usize)> {
+        let p3 = match self.p4_mut().next_table_mut(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+        if p3[page.p3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            Some((&mut p3[page.p3_index()], ENTRY_COUNT * ENTRY_COUNT))
+        } else {
+            let p2 = match p3.next_table_mut(page.p3_index()) {
+                Some(p2) => p2,
+                None => return None,
+            };
+            if p2[page.p2_index()].flags().contains(PRESENT | HUGE_PAGE) {
+                Some((&mut p2[page.p2_index()], ENTRY_COUNT))
+            } else {
+                p2.next_table_mut(page.p2_index())
+                    .map(|p1| &mut p1[page.p1_index()])
+                    .and_then(|entry| {
+                        if entry.flags().contains(PRESENT) {
+                            Some((entry, 1))
+                        } else {
+                            None
+                        }
+                    })
+            }
+        }
+    }
+
+    /// Splits the huge page that contains the given page into pages of the next smaller size.
+    fn split_huge_page<A>(&mut self,
+                          page: Page,
+                          frames_per_page: usize,
+                          allocator: &mut A)
+                          -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+        let split = if frames_per_page == ENTRY_COUNT * ENTRY_COUNT {
+            p3.next_table_create(page.p3_index(), allocator).is_some()
+        } else {
+            p3.next_table_mut(page.p3_index())
+                .unwrap()
+                .next_table_create(page.p2_index(), allocator)
+                .is_some()
+        };
+        if split {
+            Ok(())
+        } else {
+            Err(MapError::FrameAllocationFailed)
+        }
+    }
+
+    /// Sets the P1 entry of the given page and creates the page tables on the way.
+    fn set_p1_entry<A>(&mut self,
+                       page: Page,
+                       frame: Frame,
+                       flags: EntryFlags,
+                       allocator: &mut A)
+                       -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let p3 = try!(create_next_table(self.p4_mut(), page.p4_index(), allocator));
+        let p2 = try!(create_next_table(p3, page.p3_index(), allocator));
+        let p1 = try!(create_next_table(p2, page.p2_index(), allocator));
+
+        if !p1[page.p1_index()].is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        p1[page.p1_index()].set(frame, flags | PRESENT);
+        Ok(())
+    }
+
+    /// Frees the P1, P2 and P3 tables of the given page from the bottom up if they became empty.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        if let Some(p2) = self.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index())) {
+            p2.free_next_table_if_empty(page.p2_index(), allocator);
+        }
+        if let Some(p3) = self.p4_mut().next_table_mut(page.p4_index()) {
+            p3.free_next_table_if_empty(page.p3_index(), allocator);
+        }
+        self.p4_mut().free_next_table_if_empty(page.p4_index(), allocator);
+    }
+}
+
+/// Checks whether the CPU supports 1GiB pages (CPUID 0x80000001, EDX bit 26).
+fn check_1gib_pages() -> Result<(), MapError> {
+    let (_, _, _, edx) = cpuid(0x8000_0001, 0);
+    if edx & (1 << 26) != 0 {
+        Ok(())
+    } else {
+        Err(MapError::HugePageUnsupported)
+    }
+}
+
+/// Returns the next table of `table` at `index` and creates it if it doesn't exist. Unlike
+/// `Table::next_table_create`, it doesn't split huge pages, since the new mapping would overlap
+/// them.
+fn create_next_table<'a, L, A>(table: &'a mut Table<L>,
+                               index: usize,
+                               allocator: &mut A)
+                               -> Result<&'a mut Table<L::NextLevel>, MapError>
+    where L: HierarchicalLevel,
+          A: FrameAllocator
+{
+    if table[index].flags().contains(PRESENT | HUGE_PAGE) {
+        return Err(MapError::HugePageConflict);
     }
+    table.next_table_create(index, allocator).ok_or(MapError::FrameAllocationFailed)
 }
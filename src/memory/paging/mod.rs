@@ -8,19 +8,69 @@
 // except according to those terms.
 
 pub use self::entry::*;
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::{PAGE_SIZE, KERNEL_OFFSET, Frame, FrameAllocator};
 use self::temporary_page::TemporaryPage;
-pub use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MapError, UnmapError};
+pub use self::dump::Mapping;
+pub use self::virtual_allocator::{VirtualAllocator, VirtualRegion, RegionKind};
 use core::ops::{Add, Deref, DerefMut};
+#[cfg(feature = "physical_map")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "physical_map")]
+use core::cmp;
 use multiboot2::BootInformation;
+use spin::Mutex;
 
 mod entry;
 mod table;
+#[cfg_attr(feature = "physical_map", allow(dead_code))]
 mod temporary_page;
 mod mapper;
+mod dump;
+mod pcid;
+mod virtual_allocator;
 
 const ENTRY_COUNT: usize = 512;
 
+/// The P4 entry that points to the P4 table itself. The last entry is used by the kernel.
+const RECURSIVE_INDEX: usize = 510;
+
+/// The start of the map of all physical memory (P4 entry 256, the start of the upper half).
+#[cfg(feature = "physical_map")]
+pub const PHYSICAL_MAP_OFFSET: VirtualAddress = 0o177777_400_000_000_000_0000;
+
+/// The end of the physical memory that is reachable through the physical memory map. The boot
+/// page tables only map the first GiB, `remap_the_kernel` extends the map to all memory. Holes in
+/// the memory map below the end are not mapped.
+#[cfg(feature = "physical_map")]
+static PHYSICAL_MAP_END: AtomicUsize = AtomicUsize::new(ENTRY_COUNT * ENTRY_COUNT * PAGE_SIZE);
+
+/// Returns the address of the given frame in the physical memory map.
+#[cfg(feature = "physical_map")]
+fn physical_map_address(frame: &Frame) -> VirtualAddress {
+    let address = frame.start_address();
+    assert!(address < PHYSICAL_MAP_END.load(Ordering::Relaxed),
+            "frame {:#x} is not in the physical memory map",
+            address);
+    address + PHYSICAL_MAP_OFFSET
+}
+
+/// Held by `ActivePageTable::with` while the recursive entry points to an inactive table. Code
+/// that edits the active table without going through the `ActivePageTable` (see
+/// `with_active_mapper`) must not run in the meantime, because it would edit the inactive table.
+static RECURSIVE_ENTRY_SWAPPED: Mutex<()> = Mutex::new(());
+
+/// Executes `f` with a mapper for the active table, without needing the `ActivePageTable`. This
+/// is used where the memory controller might be locked, e.g. for growing the heap. Returns `None`
+/// if an `ActivePageTable::with` call is in progress.
+///
+/// The caller must make sure that it's the only one that edits the touched mappings.
+pub unsafe fn with_active_mapper<F, R>(f: F) -> Option<R>
+    where F: FnOnce(&mut Mapper) -> R
+{
+    RECURSIVE_ENTRY_SWAPPED.try_lock().map(|_guard| f(&mut Mapper::new()))
+}
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
@@ -113,6 +163,7 @@ impl ActivePageTable {
         ActivePageTable { mapper: Mapper::new() }
     }
 
+    #[cfg(not(feature = "physical_map"))]
     pub fn with<F>(&mut self,
                    table: &mut InactivePageTable,
                    temporary_page: &mut temporary_page::TemporaryPage, // new
@@ -123,34 +174,60 @@ impl ActivePageTable {
         let flush_tlb = || unsafe { tlb::flush_all() };
 
         {
+            let _guard = RECURSIVE_ENTRY_SWAPPED.lock();
             let backup = Frame::containing_address(unsafe { control_regs::cr3() } as usize);
 
             // map temporary_page to current p4 table
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
 
             // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+            self.p4_mut()[RECURSIVE_INDEX].set(table.p4_frame.clone(), PRESENT | WRITABLE);
             flush_tlb();
 
             // execute f in the new context
             f(self);
 
             // restore recursive mapping to original p4 table
-            p4_table[511].set(backup, PRESENT | WRITABLE);
+            p4_table[RECURSIVE_INDEX].set(backup, PRESENT | WRITABLE);
             flush_tlb();
         }
 
         temporary_page.unmap(self);
+        // `f` might have changed mappings that are still cached for the PCID of the table
+        table.invalidate_tlb();
+    }
+
+    /// Executes `f` with a mapper for the given inactive table. The table is reachable through
+    /// the physical memory map, so it is edited in place and the active mappings stay untouched.
+    #[cfg(feature = "physical_map")]
+    pub fn with<F>(&mut self,
+                   table: &mut InactivePageTable,
+                   _temporary_page: &mut temporary_page::TemporaryPage,
+                   f: F)
+        where F: FnOnce(&mut Mapper)
+    {
+        f(&mut unsafe { Mapper::for_table(table.p4_frame.clone()) });
+        // `f` might have changed mappings that are still cached for the PCID of the table
+        table.invalidate_tlb();
     }
 
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
         use x86::shared::control_regs;
 
+        let cr3 = unsafe { control_regs::cr3() } as usize;
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(unsafe { control_regs::cr3() } as usize),
+            p4_frame: Frame::containing_address(cr3),
+            pcid: pcid::from_cr3(cr3),
+            // the TLB entries of the active table are up to date
+            tlb_generation: Some(pcid::kernel_generation()),
         };
+        // keep the TLB entries of the new table if they are still valid
+        let flush = new_table.tlb_generation != Some(pcid::kernel_generation());
+        let p4_address = new_table.p4_frame.start_address();
         unsafe {
-            control_regs::cr3_write(new_table.p4_frame.start_address());
+            control_regs::cr3_write(pcid::cr3_value(p4_address, new_table.pcid, flush));
+            // with the physical map strategy, the mapper points to the table of the old CR3
+            self.mapper = Mapper::new();
         }
         old_table
     }
@@ -158,9 +235,15 @@ impl ActivePageTable {
 
 pub struct InactivePageTable {
     p4_frame: Frame,
+    /// The PCID of the table or 0 if it has none.
+    pcid: u16,
+    /// The kernel generation at which the table was last active, or `None` if the TLB entries of
+    /// its PCID are stale. See `pcid::KERNEL_GENERATION`.
+    tlb_generation: Option<usize>,
 }
 
 impl InactivePageTable {
+    #[cfg(not(feature = "physical_map"))]
     pub fn new(frame: Frame,
                active_table: &mut ActivePageTable,
                temporary_page: &mut TemporaryPage)
@@ -168,18 +251,172 @@ impl InactivePageTable {
         {
             let table = temporary_page.map_table_frame(frame.clone(), active_table);
             table.zero();
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
         }
         temporary_page.unmap(active_table);
 
-        InactivePageTable { p4_frame: frame }
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: pcid::allocate(),
+            tlb_generation: None,
+        }
+    }
+
+    #[cfg(feature = "physical_map")]
+    pub fn new(frame: Frame,
+               _active_table: &mut ActivePageTable,
+               _temporary_page: &mut TemporaryPage)
+               -> InactivePageTable {
+        unsafe { Mapper::for_table(frame.clone()) }.p4_mut().zero();
+
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: pcid::allocate(),
+            tlb_generation: None,
+        }
+    }
+
+    /// Invalidates the TLB entries of the table's PCID. Without the `invpcid` instruction, they
+    /// are flushed on the next switch instead.
+    fn invalidate_tlb(&mut self) {
+        if self.pcid != 0 && !pcid::flush_context(self.pcid) {
+            self.tlb_generation = None;
+        }
     }
 }
 
-pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
+/// Maps the available memory areas to `PHYSICAL_MAP_OFFSET`. Returns the end of the mapped
+/// memory.
+///
+/// The holes between the areas (e.g. the VGA buffer or PCI windows) stay unmapped. They contain
+/// device memory, which `map_mmio` maps uncached, and a second write-back mapping of the same
+/// frames would be undefined. So only the 2MiB aligned part of each area uses huge pages, the
+/// edges are mapped through 4KiB pages.
+#[cfg(feature = "physical_map")]
+fn map_physical_memory<A>(mapper: &mut Mapper,
+                          boot_info: &BootInformation,
+                          allocator: &mut A)
+                          -> PhysicalAddress
     where A: FrameAllocator
 {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe }, allocator);
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let huge_page_size = ENTRY_COUNT * PAGE_SIZE;
+    let flags = WRITABLE | NO_EXECUTE;
+
+    let mut memory_end = 0;
+    for area in memory_map_tag.memory_areas() {
+        // only whole frames of the area are mapped
+        let start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let end = (area.base_addr + area.length) as usize / PAGE_SIZE * PAGE_SIZE;
+        if start >= end {
+            continue;
+        }
+        // the map must fit into a single P4 entry
+        assert!(end <= ENTRY_COUNT * ENTRY_COUNT * ENTRY_COUNT * PAGE_SIZE,
+                "physical memory too large for the physical map");
+
+        let huge_start = (start + huge_page_size - 1) / huge_page_size * huge_page_size;
+        let huge_end = end / huge_page_size * huge_page_size;
+        if huge_start < huge_end {
+            map_physical_frames(mapper, start, huge_start, flags, allocator);
+            map_physical_range(mapper, huge_start, huge_end, flags, allocator);
+            map_physical_frames(mapper, huge_end, end, flags, allocator);
+        } else {
+            map_physical_frames(mapper, start, end, flags, allocator);
+        }
+        memory_end = cmp::max(memory_end, end);
+    }
+    memory_end
+}
+
+/// Maps the frames in the physical range `[start, end)` into the physical memory map through 4KiB
+/// pages.
+#[cfg(feature = "physical_map")]
+fn map_physical_frames<A>(mapper: &mut Mapper,
+                          start: PhysicalAddress,
+                          end: PhysicalAddress,
+                          flags: EntryFlags,
+                          allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut address = start;
+    while address < end {
+        mapper.map_to(Page::containing_address(address + PHYSICAL_MAP_OFFSET),
+                      Frame::containing_address(address),
+                      flags,
+                      allocator)
+            .expect("failed to map physical memory");
+        address += PAGE_SIZE;
+    }
+}
+
+/// Maps the 2MiB aligned physical range `[start, end)` into the physical memory map. Falls back
+/// to 2MiB pages if the CPU doesn't support 1GiB pages.
+#[cfg(feature = "physical_map")]
+fn map_physical_range<A>(mapper: &mut Mapper,
+                         start: PhysicalAddress,
+                         end: PhysicalAddress,
+                         flags: EntryFlags,
+                         allocator: &mut A)
+    where A: FrameAllocator
+{
+    let huge_page_size = ENTRY_COUNT * PAGE_SIZE;
+    let giant_page_size = ENTRY_COUNT * huge_page_size;
+    assert!(start % huge_page_size == 0 && end % huge_page_size == 0,
+            "physical range {:#x}-{:#x} is not 2MiB aligned",
+            start,
+            end);
+
+    let mut address = start;
+    while address < end {
+        let page = Page::containing_address(address + PHYSICAL_MAP_OFFSET);
+        let frame = Frame::containing_address(address);
+        if address % giant_page_size == 0 && end - address >= giant_page_size {
+            match mapper.map_to_1gib(page, frame, flags, allocator) {
+                Ok(()) => {
+                    address += giant_page_size;
+                    continue;
+                }
+                Err(MapError::HugePageUnsupported) => {}
+                Err(err) => panic!("failed to map physical memory: {:?}", err),
+            }
+        }
+        mapper.map_to_2mib(page, frame, flags, allocator).expect("failed to map physical memory");
+        address += huge_page_size;
+    }
+}
+
+#[cfg(not(feature = "physical_map"))]
+fn map_physical_memory<A>(_mapper: &mut Mapper,
+                          _boot_info: &BootInformation,
+                          _allocator: &mut A)
+                          -> PhysicalAddress
+    where A: FrameAllocator
+{
+    0
+}
+
+/// Sets the end of the physical memory map when switching to the table that maps all memory.
+#[cfg(feature = "physical_map")]
+fn set_physical_map_end(end: PhysicalAddress) {
+    PHYSICAL_MAP_END.store(end, Ordering::Relaxed);
+}
+
+#[cfg(not(feature = "physical_map"))]
+fn set_physical_map_end(_end: PhysicalAddress) {}
+
+pub fn remap_the_kernel<A>(allocator: &mut A,
+                           virtual_allocator: &mut VirtualAllocator,
+                           boot_info: &BootInformation)
+                           -> ActivePageTable
+    where A: FrameAllocator
+{
+    let temporary_region = virtual_allocator.allocate(1, RegionKind::Temporary)
+        .expect("no virtual memory for temporary page");
+    let mut temporary_page = TemporaryPage::new(temporary_region.start_page(), allocator);
+
+    // enable PCIDs before the new table is created, so that it gets its own PCID
+    pcid::init();
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
@@ -187,16 +424,21 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
         InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
     };
 
+    let mut physical_map_end = 0;
     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
         let elf_sections_tag = boot_info.elf_sections_tag()
             .expect("Memory map tag required");
 
-        // identity map the allocated kernel sections
+        // map the allocated kernel sections to their addresses in the higher half
         for section in elf_sections_tag.sections() {
             if !section.is_allocated() {
                 // section is not loaded to memory
                 continue;
             }
+            if (section.addr as usize) < KERNEL_OFFSET {
+                // the boot code is only needed until we jump to the higher half
+                continue;
+            }
 
             assert!(section.addr as usize % PAGE_SIZE == 0,
                     "sections need to be page aligned");
@@ -206,31 +448,39 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
 
             let flags = EntryFlags::from_elf_section_flags(section);
 
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
+            let start_frame = Frame::containing_address(section.start_address() - KERNEL_OFFSET);
+            let end_frame = Frame::containing_address(section.end_address() - 1 - KERNEL_OFFSET);
             for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
+                mapper.higher_half_map(frame, flags, allocator).expect("failed to map the kernel");
             }
         }
 
-        // identity map the VGA text buffer
+        // map the VGA text buffer
         let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+        mapper.higher_half_map(vga_buffer_frame, WRITABLE, allocator)
+            .expect("failed to map the VGA buffer");
 
-        // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
+        // map the multiboot info structure
+        let multiboot_start = Frame::containing_address(boot_info.start_address() - KERNEL_OFFSET);
+        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1 - KERNEL_OFFSET);
         for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
+            mapper.higher_half_map(frame, PRESENT, allocator)
+                .expect("failed to map the multiboot information");
         }
+
+        physical_map_end = map_physical_memory(mapper, boot_info, allocator);
     });
 
+    // `switch` already reaches the new P4 table through the physical memory map of the new table
+    set_physical_map_end(physical_map_end);
     let old_table = active_table.switch(new_table);
     println!("NEW TABLE!!!");
 
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
+    // the old p4 frame is part of the kernel's .bss section, so we don't free it
+    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address() + KERNEL_OFFSET);
+    active_table.unmap(old_p4_page, allocator).expect("old P4 table is not mapped");
     println!("guard page at {:#x}", old_p4_page.start_address());
 
+    virtual_allocator.free(temporary_region);
     active_table
 }
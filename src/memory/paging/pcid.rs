@@ -0,0 +1,149 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Process-context identifiers (PCIDs). With PCIDs enabled, the CPU tags every TLB entry with
+//! the PCID of the address space it belongs to, so switching address spaces no longer needs to
+//! flush the TLB.
+//!
+//! PCID 0 is shared by all page tables that don't have their own PCID. Switching to such a table
+//! always flushes the TLB, just like without PCID support.
+
+use super::VirtualAddress;
+use memory::cpuid;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+/// The number of available PCIDs. The PCID is stored in the lower 12 bits of CR3.
+const PCID_COUNT: usize = 4096;
+/// If this bit is set in the value written to CR3, the TLB entries of the new PCID are kept.
+const CR3_NO_FLUSH: usize = 1 << 63;
+
+/// The INVPCID type that invalidates all entries of a single PCID (except for global pages).
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+
+struct Support {
+    pcid: bool,
+    invpcid: bool,
+}
+
+static SUPPORT: Once<Support> = Once::new();
+
+/// The next unused PCID. PCIDs are never reused, since we never free page tables.
+static NEXT_PCID: AtomicUsize = AtomicUsize::new(1);
+
+/// Counts changes to kernel mappings. The `invlpg` instruction only invalidates the page for the
+/// current PCID, so the other PCIDs might still cache the old kernel mappings. Page tables
+/// remember the generation at which they were last active and are flushed on the next switch if
+/// it changed.
+static KERNEL_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Enables PCIDs if the CPU supports them. Must be called before any PCID is allocated and while
+/// the lower 12 bits of CR3 are zero.
+pub fn init() {
+    use x86::shared::control_regs::{cr4, cr4_write, CR4_ENABLE_PCID};
+
+    SUPPORT.call_once(|| {
+        let (_, _, ecx, _) = cpuid(1, 0);
+        if ecx & (1 << 17) == 0 {
+            return Support {
+                pcid: false,
+                invpcid: false,
+            };
+        }
+        unsafe { cr4_write(cr4() | CR4_ENABLE_PCID) };
+
+        let (_, ebx, _, _) = cpuid(7, 0);
+        Support {
+            pcid: true,
+            invpcid: ebx & (1 << 10) != 0,
+        }
+    });
+}
+
+/// Returns whether PCIDs are enabled.
+pub fn enabled() -> bool {
+    SUPPORT.try().map(|support| support.pcid).unwrap_or(false)
+}
+
+/// Returns a new PCID or 0 if PCIDs are not supported or all of them are in use.
+pub fn allocate() -> u16 {
+    if !enabled() {
+        return 0;
+    }
+    let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed);
+    if pcid < PCID_COUNT {
+        pcid as u16
+    } else {
+        NEXT_PCID.store(PCID_COUNT, Ordering::Relaxed); // avoid an overflow
+        0
+    }
+}
+
+/// Returns the current generation of the kernel mappings.
+pub fn kernel_generation() -> usize {
+    KERNEL_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Returns the value for CR3 that activates the P4 table at `p4_address` with the given PCID.
+/// If `flush` is false, the TLB entries of the PCID are kept.
+pub fn cr3_value(p4_address: usize, pcid: u16, flush: bool) -> usize {
+    if pcid == 0 || flush {
+        p4_address | pcid as usize
+    } else {
+        p4_address | pcid as usize | CR3_NO_FLUSH
+    }
+}
+
+/// Returns the PCID that is encoded in the given CR3 value.
+pub fn from_cr3(cr3: usize) -> u16 {
+    if enabled() {
+        (cr3 & (PCID_COUNT - 1)) as u16
+    } else {
+        0
+    }
+}
+
+/// Invalidates all TLB entries of the given (inactive) PCID. Returns `false` if the CPU doesn't
+/// support the `invpcid` instruction. Then the caller needs to flush the PCID on the next switch.
+pub fn flush_context(pcid: u16) -> bool {
+    match SUPPORT.try() {
+        Some(&Support { invpcid: true, .. }) => {
+            let descriptor: [u64; 2] = [pcid as u64, 0];
+            unsafe {
+                asm!("invpcid ($1), $0"
+                     :: "r"(INVPCID_SINGLE_CONTEXT), "r"(&descriptor) : "memory" : "volatile")
+            };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Invalidates the TLB entry of the given page. Other PCIDs are flushed on their next switch if
+/// the page belongs to the kernel.
+pub fn flush_page(address: VirtualAddress) {
+    unsafe { ::x86::shared::tlb::flush(address) };
+    if address & (1 << 63) != 0 {
+        KERNEL_GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Invalidates the TLB entry of a page that was mapped and unmapped while the current address
+/// space stayed active, e.g. a `TemporaryPage`. Other PCIDs never saw the mapping, so they don't
+/// need to be flushed.
+pub fn flush_local_page(address: VirtualAddress) {
+    unsafe { ::x86::shared::tlb::flush(address) };
+}
+
+/// Invalidates all TLB entries of the current PCID (except for global pages). The other PCIDs
+/// are flushed on their next switch, since kernel mappings might have changed.
+pub fn flush_all() {
+    unsafe { ::x86::shared::tlb::flush_all() };
+    KERNEL_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
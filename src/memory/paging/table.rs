@@ -9,11 +9,15 @@
 
 use memory::paging::entry::*;
 use memory::paging::ENTRY_COUNT;
-use memory::FrameAllocator;
+#[cfg(feature = "physical_map")]
+use memory::paging::physical_map_address;
+use memory::{Frame, FrameAllocator};
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+/// The address of the P4 table through the recursive entry (`RECURSIVE_INDEX` in all four levels).
+#[cfg(not(feature = "physical_map"))]
+pub const P4: *mut Table<Level4> = 0o177777_776_776_776_776_0000 as *mut _;
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -28,16 +32,33 @@ impl<L> Table<L>
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L>
     where L: HierarchicalLevel
 {
+    #[cfg(not(feature = "physical_map"))]
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            let address = ((table_address << 9) | (index << 12)) & 0x0000_ffff_ffff_ffff;
+            // sign extend: the recursive entry lies in the upper half, so bit 47 is always set
+            Some(address | 0xffff_0000_0000_0000)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(feature = "physical_map")]
+    fn next_table_address(&self, index: usize) -> Option<usize> {
+        let entry_flags = self[index].flags();
+        if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+            Some(physical_map_address(&self[index].pointed_frame().unwrap()))
         } else {
             None
         }
@@ -53,20 +74,125 @@ impl<L> Table<L>
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
+    /// Returns the next table at `index`. It is created if it doesn't exist yet and huge pages
+    /// are split. Returns `None` if there is no frame for the new table.
     pub fn next_table_create<A>(&mut self,
                                 index: usize,
                                 allocator: &mut A)
-                                -> &mut Table<L::NextLevel>
+                                -> Option<&mut Table<L::NextLevel>>
         where A: FrameAllocator
     {
-        if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(HUGE_PAGE),
-                    "mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("no frames available");
+        if self.entries[index].flags().contains(HUGE_PAGE) {
+            if !self.split_huge_page(index, allocator) {
+                return None;
+            }
+        } else if self.next_table(index).is_none() {
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return None,
+            };
             self.entries[index].set(frame, PRESENT | WRITABLE);
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        self.next_table_mut(index)
+    }
+
+    /// Replaces the huge page at `index` with a next level table that maps the same memory with
+    /// the same flags.
+    ///
+    /// The translation stays the same, so stale TLB entries for the huge page are harmless until
+    /// one of the new entries is changed. Changing an entry requires flushing the affected page
+    /// anyway, which also removes the TLB entry of the huge page.
+    ///
+    /// Returns `false` if there is no frame for the new table.
+    fn split_huge_page<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let flags = self.entries[index].huge_page_flags();
+        let start_frame = self.entries[index].huge_page_frame().unwrap();
+        let frames_per_entry = L::NextLevel::frames_per_entry();
+        // bit 7 is the PAT bit in P1 entries, so the PAT bit of the huge page moves there
+        let entry_flags = if frames_per_entry == 1 { flags.huge_to_p1() } else { flags };
+
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        self.entries[index].set(frame, PRESENT | WRITABLE | (flags & USER_ACCESSIBLE));
+
+        // the recursive address of the new table might still be cached as part of the huge page
+        let table_address = self.next_table_address(index).unwrap();
+        unsafe { ::x86::shared::tlb::flush(table_address) };
+
+        let table = self.next_table_mut(index).unwrap();
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            let frame = Frame { number: start_frame.number + i * frames_per_entry };
+            entry.set(frame, entry_flags);
+        }
+        true
+    }
+
+    /// Merges the next table at `index` into a single huge page if its entries map contiguous,
+    /// aligned memory with identical flags. Returns `false` if the entries are not uniform.
+    pub fn collapse_next_table<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let frames_per_entry = L::NextLevel::frames_per_entry();
+        // returns the frame of an entry of the next table and its flags as huge page flags. The
+        // hardware sets the accessed and dirty flags, so they don't need to match.
+        let frame_and_flags = |entry: &Entry| -> Option<(Frame, EntryFlags)> {
+            if frames_per_entry > 1 {
+                // entries of a P2 table must be huge pages themselves
+                entry.huge_page_frame()
+                    .map(|frame| (frame, entry.huge_page_flags() - ACCESSED - DIRTY))
+            } else {
+                entry.pointed_frame()
+                    .map(|frame| (frame, (entry.flags() - ACCESSED - DIRTY).p1_to_huge()))
+            }
+        };
+        let (start_frame, flags) = {
+            let table = match self.next_table(index) {
+                Some(table) => table,
+                None => return false,
+            };
+            let (start_frame, flags) = match frame_and_flags(&table[0]) {
+                Some(frame_and_flags) => frame_and_flags,
+                None => return false,
+            };
+
+            if start_frame.number % (frames_per_entry * ENTRY_COUNT) != 0 {
+                return false;
+            }
+            for (i, entry) in table.entries.iter().enumerate() {
+                let frame = Frame { number: start_frame.number + i * frames_per_entry };
+                if frame_and_flags(entry) != Some((frame, flags)) {
+                    return false;
+                }
+            }
+            (start_frame, flags)
+        };
+
+        let table_frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set(start_frame, flags);
+        // the old entries and the recursive address of the table are no longer valid
+        super::pcid::flush_all();
+        allocator.deallocate_frame(table_frame);
+        true
+    }
+
+    /// Frees the next table at `index` if it has no used entries.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let table_address = match self.next_table(index) {
+            Some(table) if table.is_empty() => table as *const _ as usize,
+            _ => return,
+        };
+        let frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set_unused();
+        // the table is no longer reachable through the recursive mapping
+        unsafe { ::x86::shared::tlb::flush(table_address) };
+        allocator.deallocate_frame(frame);
     }
 }
 
@@ -88,7 +214,10 @@ impl<L> IndexMut<usize> for Table<L>
     }
 }
 
-pub trait TableLevel {}
+pub trait TableLevel {
+    /// Returns the number of frames that a single entry of a table of this level maps.
+    fn frames_per_entry() -> usize;
+}
 
 pub enum Level4 {}
 #[allow(dead_code)]
@@ -97,10 +226,26 @@ pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
-impl TableLevel for Level4 {}
-impl TableLevel for Level3 {}
-impl TableLevel for Level2 {}
-impl TableLevel for Level1 {}
+impl TableLevel for Level4 {
+    fn frames_per_entry() -> usize {
+        ENTRY_COUNT * ENTRY_COUNT * ENTRY_COUNT
+    }
+}
+impl TableLevel for Level3 {
+    fn frames_per_entry() -> usize {
+        ENTRY_COUNT * ENTRY_COUNT
+    }
+}
+impl TableLevel for Level2 {
+    fn frames_per_entry() -> usize {
+        ENTRY_COUNT
+    }
+}
+impl TableLevel for Level1 {
+    fn frames_per_entry() -> usize {
+        1
+    }
+}
 
 pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
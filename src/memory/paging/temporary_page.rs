@@ -33,7 +33,8 @@ impl TemporaryPage {
 
         assert!(active_table.translate_page(self.page).is_none(),
                 "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
+        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator)
+            .expect("failed to map the temporary page");
         self.page.start_address()
     }
 
@@ -48,13 +49,16 @@ impl TemporaryPage {
 
     /// Unmaps the temporary page in the active table.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        // the mapped frame is only borrowed, so we must not free it
+        active_table.unmap_temporary(self.page, &mut self.allocator)
+            .expect("temporary page is not mapped");
     }
 }
 
 struct TinyAllocator([Option<Frame>; 3]);
 
 impl TinyAllocator {
+    #[cfg(not(feature = "physical_map"))]
     fn new<A>(allocator: &mut A) -> TinyAllocator
         where A: FrameAllocator
     {
@@ -62,6 +66,15 @@ impl TinyAllocator {
         let frames = [f(), f(), f()];
         TinyAllocator(frames)
     }
+
+    // with the physical memory map, inactive tables are edited in place, so the temporary page is
+    // never mapped and needs no frames for page tables
+    #[cfg(feature = "physical_map")]
+    fn new<A>(_allocator: &mut A) -> TinyAllocator
+        where A: FrameAllocator
+    {
+        TinyAllocator([None, None, None])
+    }
 }
 
 impl FrameAllocator for TinyAllocator {
@@ -0,0 +1,177 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Page, VirtualAddress};
+
+/// The start of the virtual address window that is managed by the `VirtualAllocator`. It covers
+/// the P4 entry below the recursive entry, so that the lower half stays free for user processes.
+const KERNEL_WINDOW_START: VirtualAddress = 0o177777_775_000_000_000_0000;
+/// The end of the window (exclusive), i.e. the start of the recursive P4 entry.
+const KERNEL_WINDOW_END: VirtualAddress = 0o177777_776_000_000_000_0000;
+
+/// The maximum number of regions. We need the allocator before the heap is initialized, so the
+/// regions are stored in a fixed size array.
+const MAX_REGIONS: usize = 64;
+
+/// The purpose of a virtual region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Modules,
+    Mmio,
+    FrameBitmap,
+    Temporary,
+}
+
+/// A range of pages in the kernel window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    start: Page,
+    page_count: usize,
+    kind: RegionKind,
+}
+
+impl VirtualRegion {
+    pub fn start_page(&self) -> Page {
+        self.start
+    }
+
+    /// Returns the last page of the region (inclusive).
+    pub fn end_page(&self) -> Page {
+        self.start + (self.page_count - 1)
+    }
+
+    pub fn contains(&self, page: Page) -> bool {
+        page >= self.start && page.number < self.end_number()
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    // the number of the first page behind the region
+    fn end_number(&self) -> usize {
+        self.start.number + self.page_count
+    }
+}
+
+/// Hands out page ranges of the kernel window, so that the heap, stacks, temporary pages, and
+/// device mappings never overlap.
+///
+/// The allocator only manages virtual addresses. Mapping the returned regions is up to the
+/// caller.
+pub struct VirtualAllocator {
+    // the used regions, sorted by their start page
+    regions: [VirtualRegion; MAX_REGIONS],
+    region_count: usize,
+}
+
+impl VirtualAllocator {
+    pub fn new() -> VirtualAllocator {
+        let unused = VirtualRegion {
+            start: Page { number: 0 },
+            page_count: 0,
+            kind: RegionKind::Temporary,
+        };
+        VirtualAllocator {
+            regions: [unused; MAX_REGIONS],
+            region_count: 0,
+        }
+    }
+
+    /// Returns all used regions, sorted by their start address.
+    pub fn regions(&self) -> &[VirtualRegion] {
+        &self.regions[..self.region_count]
+    }
+
+    /// Reserves the given fixed range of pages. Returns `None` if the range lies outside of the
+    /// kernel window or overlaps an existing region.
+    pub fn reserve(&mut self,
+                   start: Page,
+                   page_count: usize,
+                   kind: RegionKind)
+                   -> Option<VirtualRegion> {
+        let region = VirtualRegion {
+            start: start,
+            page_count: page_count,
+            kind: kind,
+        };
+        let window_start = Page::containing_address(KERNEL_WINDOW_START);
+        let window_end = Page::containing_address(KERNEL_WINDOW_END);
+
+        if page_count == 0 || start < window_start || region.end_number() > window_end.number {
+            return None;
+        }
+        // the index of the first region that ends behind the start of the new region
+        let index = self.regions()
+            .iter()
+            .position(|r| r.end_number() > start.number)
+            .unwrap_or(self.region_count);
+        if index < self.region_count && self.regions[index].start.number < region.end_number() {
+            return None; // overlap
+        }
+        self.insert(index, region)
+    }
+
+    /// Allocates a region of `page_count` pages at the lowest free address.
+    pub fn allocate(&mut self, page_count: usize, kind: RegionKind) -> Option<VirtualRegion> {
+        if page_count == 0 {
+            return None;
+        }
+        let window_end = Page::containing_address(KERNEL_WINDOW_END);
+
+        // find the first gap that is large enough
+        let mut gap_start = Page::containing_address(KERNEL_WINDOW_START).number;
+        let mut index = 0;
+        while index < self.region_count {
+            let next = self.regions[index];
+            if next.start.number - gap_start >= page_count {
+                break;
+            }
+            gap_start = next.end_number();
+            index += 1;
+        }
+        if index == self.region_count && window_end.number - gap_start < page_count {
+            return None;
+        }
+
+        let region = VirtualRegion {
+            start: Page { number: gap_start },
+            page_count: page_count,
+            kind: kind,
+        };
+        self.insert(index, region)
+    }
+
+    /// Frees a region that was returned by `reserve` or `allocate`.
+    pub fn free(&mut self, region: VirtualRegion) {
+        let index = self.regions()
+            .iter()
+            .position(|&r| r == region)
+            .expect("freed virtual region was not allocated");
+
+        for i in index..self.region_count - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.region_count -= 1;
+    }
+
+    fn insert(&mut self, index: usize, region: VirtualRegion) -> Option<VirtualRegion> {
+        if self.region_count == MAX_REGIONS {
+            return None;
+        }
+        for i in (index..self.region_count).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+        self.regions[index] = region;
+        self.region_count += 1;
+        Some(region)
+    }
+}
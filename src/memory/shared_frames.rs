@@ -0,0 +1,92 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{Frame, FrameAllocator};
+
+/// The maximum number of frames that can be shared copy-on-write at the same time.
+const MAX_SHARED_FRAMES: usize = 256;
+
+/// The number of mappings of each frame that is shared copy-on-write by more than one page.
+///
+/// The table has a fixed size, because it is updated while the memory controller is locked, so
+/// it must not allocate heap memory (the heap might need the memory controller to grow).
+pub struct SharedFrames {
+    // frame numbers and their mapping counts. A count of 0 marks an unused slot.
+    entries: [(usize, usize); MAX_SHARED_FRAMES],
+}
+
+impl SharedFrames {
+    pub fn new() -> SharedFrames {
+        SharedFrames { entries: [(0, 0); MAX_SHARED_FRAMES] }
+    }
+
+    /// Returns the number of pages that map the given frame. Frames that aren't shared have a
+    /// single mapping.
+    pub fn mappings(&self, frame: &Frame) -> usize {
+        self.entries
+            .iter()
+            .find(|&&(number, count)| count > 0 && number == frame.number)
+            .map(|&(_, count)| count)
+            .unwrap_or(1)
+    }
+
+    /// Adds a mapping of the given frame. Returns `false` if the table is full.
+    pub fn share(&mut self, frame: &Frame) -> bool {
+        if let Some(entry) = self.entries
+            .iter_mut()
+            .find(|&&mut (number, count)| count > 0 && number == frame.number) {
+            entry.1 += 1;
+            return true;
+        }
+        match self.entries.iter_mut().find(|&&mut (_, count)| count == 0) {
+            Some(entry) => {
+                *entry = (frame.number, 2);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes a mapping of the given frame. Returns `true` if it was the last mapping, so that
+    /// the frame can be freed.
+    pub fn release(&mut self, frame: &Frame) -> bool {
+        match self.entries
+            .iter_mut()
+            .find(|&&mut (number, count)| count > 0 && number == frame.number) {
+            Some(entry) => {
+                // a frame with a single mapping left isn't shared anymore
+                entry.1 -= 1;
+                if entry.1 == 1 {
+                    entry.1 = 0;
+                }
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// A frame allocator that only frees frames when their last copy-on-write mapping is gone. Use it
+/// for unmapping pages that might be shared.
+pub struct SharingAllocator<'a, A: 'a> {
+    pub allocator: &'a mut A,
+    pub shared_frames: &'a mut SharedFrames,
+}
+
+impl<'a, A: FrameAllocator> FrameAllocator for SharingAllocator<'a, A> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocator.allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if self.shared_frames.release(&frame) {
+            self.allocator.deallocate_frame(frame);
+        }
+    }
+}
@@ -1,13 +1,18 @@
-use memory::paging::{self, Page, PageIter, ActivePageTable};
+use memory::paging::{self, Page, ActivePageTable, VirtualAllocator, VirtualRegion, RegionKind};
 use memory::{PAGE_SIZE, FrameAllocator};
 
 pub struct StackAllocator {
-    range: PageIter,
+    mapped_pages: usize,
 }
 
 impl StackAllocator {
-    pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+    pub fn new() -> StackAllocator {
+        StackAllocator { mapped_pages: 0 }
+    }
+
+    /// Returns the number of stack pages that are backed by frames (guard pages excluded).
+    pub fn mapped_pages(&self) -> usize {
+        self.mapped_pages
     }
 }
 
@@ -15,42 +20,57 @@ impl StackAllocator {
     pub fn alloc_stack<FA: FrameAllocator>(&mut self,
                                            active_table: &mut ActivePageTable,
                                            frame_allocator: &mut FA,
+                                           virtual_allocator: &mut VirtualAllocator,
                                            size_in_pages: usize)
                                            -> Option<Stack> {
         if size_in_pages == 0 {
             return None; /* a zero sized stack makes no sense */
         }
 
-        // clone the range, since we only want to change it on success
-        let mut range = self.range.clone();
-
-        // try to allocate the stack pages and a guard page
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
-        } else {
-            // choose the (size_in_pages-2)th element, since index
-            // starts at 0 and we already allocated the start page
-            range.nth(size_in_pages - 2)
+        // allocate the stack pages and a guard page below them
+        let region = match virtual_allocator.allocate(size_in_pages + 1, RegionKind::Stack) {
+            Some(region) => region,
+            None => return None, /* not enough pages */
         };
+        let start = region.start_page() + 1;
+        let end = region.end_page();
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                // success! write back updated range
-                self.range = range;
-
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::WRITABLE, frame_allocator);
+        // map stack pages to physical frames
+        for page in Page::range_inclusive(start, end) {
+            if active_table.map(page, paging::WRITABLE, frame_allocator).is_err() {
+                // not enough frames, undo the mappings of the previous pages
+                for mapped in Page::range_inclusive(start, end).take_while(|&p| p < page) {
+                    let frame = active_table.unmap(mapped, frame_allocator).unwrap();
+                    frame_allocator.deallocate_frame(frame);
                 }
-
-                // create a new stack
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+                virtual_allocator.free(region);
+                return None;
             }
-            _ => None, /* not enough pages */
         }
+        self.mapped_pages += size_in_pages;
+
+        // create a new stack
+        let top_of_stack = end.start_address() + PAGE_SIZE;
+        Some(Stack::new(top_of_stack, start.start_address(), region))
+    }
+
+    /// Unmaps the given stack, frees its frames, and makes its pages (including the guard page)
+    /// available for new stacks. The stack must not be in use anymore.
+    pub fn free_stack<FA: FrameAllocator>(&mut self,
+                                          stack: Stack,
+                                          active_table: &mut ActivePageTable,
+                                          frame_allocator: &mut FA,
+                                          virtual_allocator: &mut VirtualAllocator) {
+        let start = Page::containing_address(stack.bottom);
+        let end = Page::containing_address(stack.top - 1);
+
+        for page in Page::range_inclusive(start, end) {
+            let frame = active_table.unmap(page, frame_allocator)
+                .expect("stack page is not mapped");
+            frame_allocator.deallocate_frame(frame);
+            self.mapped_pages -= 1;
+        }
+        virtual_allocator.free(stack.region);
     }
 }
 
@@ -58,14 +78,17 @@ impl StackAllocator {
 pub struct Stack {
     top: usize,
     bottom: usize,
+    // the stack pages and the guard page
+    region: VirtualRegion,
 }
 
 impl Stack {
-    fn new(top: usize, bottom: usize) -> Stack {
+    fn new(top: usize, bottom: usize, region: VirtualRegion) -> Stack {
         assert!(top > bottom);
         Stack {
             top: top,
             bottom: bottom,
+            region: region,
         }
     }
 
@@ -73,7 +96,6 @@ impl Stack {
         self.top
     }
 
-    #[allow(dead_code)]
     pub fn bottom(&self) -> usize {
         self.bottom
     }
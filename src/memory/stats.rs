@@ -0,0 +1,118 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::PAGE_SIZE;
+use memory::paging::PhysicalAddress;
+use multiboot2::MemoryMapTag;
+use collections::Vec;
+use core::{fmt, mem};
+
+/// A snapshot of the physical memory usage, counted in frames.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub usable_frames: usize,
+    pub free_frames: usize,
+    pub kernel_frames: usize,
+    pub page_table_frames: usize,
+    pub heap_frames: usize,
+    pub stack_frames: usize,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kib = |frames: usize| frames * PAGE_SIZE / 1024;
+        write!(f,
+               "memory: {} KiB usable, {} KiB free\n  kernel: {} KiB, page tables: {} KiB, heap: \
+                {} KiB, stacks: {} KiB",
+               kib(self.usable_frames),
+               kib(self.free_frames),
+               kib(self.kernel_frames),
+               kib(self.page_table_frames),
+               kib(self.heap_frames),
+               kib(self.stack_frames))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAreaType {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+    Unknown(u32),
+}
+
+impl MemoryAreaType {
+    fn from_multiboot(typ: u32) -> MemoryAreaType {
+        match typ {
+            1 => MemoryAreaType::Available,
+            2 => MemoryAreaType::Reserved,
+            3 => MemoryAreaType::AcpiReclaimable,
+            4 => MemoryAreaType::AcpiNvs,
+            5 => MemoryAreaType::Defective,
+            typ => MemoryAreaType::Unknown(typ),
+        }
+    }
+}
+
+/// An entry of the multiboot memory map. In contrast to `multiboot2::MemoryArea`, it includes
+/// the areas that are not available for general use.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub start_address: PhysicalAddress,
+    pub size: usize,
+    pub typ: MemoryAreaType,
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:#x}-{:#x} {:?}",
+               self.start_address,
+               self.start_address + self.size,
+               self.typ)
+    }
+}
+
+/// Returns all entries of the memory map tag.
+///
+/// The `MemoryAreaIter` of the multiboot2 crate skips all areas that are not available, so we
+/// read the entries ourselves. The tag consists of a 16 byte header (type, size, entry size,
+/// entry version) followed by the entries.
+pub fn memory_regions(tag: &MemoryMapTag) -> Vec<MemoryRegion> {
+    #[repr(C)]
+    struct RawEntry {
+        base_addr: u64,
+        length: u64,
+        typ: u32,
+        _reserved: u32,
+    }
+
+    let tag_address = tag as *const _ as usize;
+    let tag_size = unsafe { *((tag_address + 4) as *const u32) } as usize;
+    let entry_size = unsafe { *((tag_address + 8) as *const u32) } as usize;
+
+    let mut regions = Vec::new();
+    // a zero entry size would loop forever and smaller entries would overlap
+    if entry_size < mem::size_of::<RawEntry>() {
+        return regions;
+    }
+    let mut entry_address = tag_address + 16;
+    while entry_address + entry_size <= tag_address + tag_size {
+        let entry = unsafe { &*(entry_address as *const RawEntry) };
+        regions.push(MemoryRegion {
+            start_address: entry.base_addr as usize,
+            size: entry.length as usize,
+            typ: MemoryAreaType::from_multiboot(entry.typ),
+        });
+        entry_address += entry_size;
+    }
+    regions
+}